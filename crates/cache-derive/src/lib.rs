@@ -0,0 +1,74 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, parse_macro_input, spanned::Spanned};
+
+/// Implements `cache::Index` for a struct with named fields. One field, an
+/// `Option<PathBuf>`, must be marked `#[index(value_path)]`; it is left `None`
+/// in lookup keys and set to the content path on save.
+#[proc_macro_derive(Index, attributes(index))]
+pub fn derive_index(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "Index can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            input.span(),
+            "Index can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut value_path = None;
+    for field in &fields.named {
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("index")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("value_path") {
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown index attribute, expected `value_path`"))
+                }
+            })?;
+            if value_path.is_some() {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "only one field can be marked #[index(value_path)]",
+                ));
+            }
+            value_path = field.ident.clone();
+        }
+    }
+    let Some(value_path) = value_path else {
+        return Err(syn::Error::new(
+            input.span(),
+            "missing a field marked #[index(value_path)] to hold the cached value's path",
+        ));
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let missing = format!("missing {} in {}", value_path, name);
+
+    Ok(quote! {
+        impl #impl_generics ::cache::Index for #name #ty_generics #where_clause {
+            fn set_value_hash_path(&mut self, path: ::std::path::PathBuf) {
+                self.#value_path = ::std::option::Option::Some(path);
+            }
+
+            fn get_value_hash_path(&self) -> ::cache::__private::anyhow::Result<::std::path::PathBuf> {
+                self.#value_path
+                    .clone()
+                    .ok_or_else(|| ::cache::__private::anyhow::anyhow!(#missing))
+            }
+        }
+    })
+}
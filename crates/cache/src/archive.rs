@@ -0,0 +1,166 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{Cache, content, index};
+
+const INDEX_DIR: &str = "index";
+const CONTENT_DIR: &str = "content";
+
+/// What an import brought in that wasnt already there
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub entries: usize,
+    pub contents: usize,
+}
+
+impl Cache {
+    /// Writes every bucket, history included, and all content to a tar archive.
+    /// Content goes in as stored, so compressed blobs stay compressed.
+    pub fn export(&self, writer: impl Write) -> anyhow::Result<()> {
+        let mut archive = tar::Builder::new(writer);
+        for bucket in self.storage.buckets()? {
+            let Some(raw) = self.storage.read_index(&bucket)? else {
+                continue;
+            };
+            append(
+                &mut archive,
+                &Path::new(INDEX_DIR).join(&bucket),
+                raw.as_bytes(),
+            )?;
+        }
+        for key in self.storage.content_keys()? {
+            let mut content = Vec::new();
+            self.storage.read_content(&key)?.read_to_end(&mut content)?;
+            append(&mut archive, &Path::new(CONTENT_DIR).join(&key), &content)?;
+        }
+        archive.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Merges an archive from `export` into this cache. Index lines already present
+    /// are skipped, and content is verified before it is stored.
+    pub fn import(&self, reader: impl Read) -> anyhow::Result<ImportSummary> {
+        let mut summary = ImportSummary::default();
+        let mut archive = tar::Archive::new(reader);
+        for file in archive.entries()? {
+            let mut file = file?;
+            let path: PathBuf = file.path()?.into_owned();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+
+            if let Ok(bucket) = path.strip_prefix(INDEX_DIR) {
+                // names come from the archive, so only ever accept ones `index::bucket` makes
+                let bucket = bucket
+                    .to_str()
+                    .filter(|bucket| index::is_bucket(bucket))
+                    .with_context(|| format!("bad bucket in archive at {}", path.display()))?;
+                let raw = String::from_utf8(data)?;
+                summary.entries += index::merge_bucket(&*self.storage, bucket, &raw)?;
+            } else if let Ok(key) = path.strip_prefix(CONTENT_DIR) {
+                let (integrity, compression) = content::parse_key(key)?;
+                if content::content_key(&integrity, compression)? != key {
+                    anyhow::bail!("bad content key in archive at {}", path.display());
+                }
+                if self.storage.has_content(key)? {
+                    continue;
+                }
+                let mut decoded = Vec::new();
+                compression
+                    .decoder(Box::new(data.as_slice()))?
+                    .read_to_end(&mut decoded)?;
+                integrity
+                    .check(&decoded)
+                    .with_context(|| format!("corrupt content in archive at {}", path.display()))?;
+                self.storage.write_content(key, &data)?;
+                summary.contents += 1;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+fn append(archive: &mut tar::Builder<impl Write>, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CacheOptions, Compression, MemoryStorage, testing::Key};
+
+    #[test]
+    fn export_then_import() {
+        let source = Cache::with_storage(
+            MemoryStorage::new(),
+            CacheOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        );
+        source.save(&mut Key::new("one"), "first").unwrap();
+        source.save(&mut Key::new("two"), "second").unwrap();
+        source
+            .save(&mut Key::new("two"), "second, updated")
+            .unwrap();
+
+        let mut archive = Vec::new();
+        source.export(&mut archive).unwrap();
+
+        let target = Cache::in_memory();
+        let summary = target.import(archive.as_slice()).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                entries: 3,
+                contents: 3
+            }
+        );
+        assert_eq!(target.get(&Key::new("two")).unwrap(), b"second, updated");
+        assert_eq!(target.history(&Key::new("two")).unwrap().len(), 2);
+
+        // importing the same archive again changes nothing
+        let summary = target.import(archive.as_slice()).unwrap();
+        assert_eq!(summary, ImportSummary::default());
+    }
+
+    /// An archive with one file, at a path `tar::Builder` would refuse to write
+    fn crafted(path: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut archive = tar::Builder::new(Vec::new());
+        archive.append(&header, data).unwrap();
+        archive.into_inner().unwrap()
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_cache() {
+        let cache = Cache::in_memory();
+        for path in [
+            "index/../../escape",
+            "index//etc/passwd",
+            "index/ab",
+            "index/é",
+            "content/../../escape",
+            "content/sha256/../../../escape",
+            "content/sha256/x",
+            "content/sha256/éé",
+        ] {
+            let archive = crafted(path, b"data");
+            assert!(cache.import(archive.as_slice()).is_err(), "{}", path);
+        }
+        assert!(cache.storage().buckets().unwrap().is_empty());
+        assert!(cache.storage().content_keys().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Context;
+
+use crate::options::Fsync;
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const LOCK_FILE: &str = ".lock";
+
+/// Writes `contents` to a temp file next to `path` and renames it into place,
+/// so readers only ever see the old file or the complete new one.
+pub(crate) fn write_atomic(
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+    fsync: Fsync,
+) -> anyhow::Result<()> {
+    let parent = path
+        .parent()
+        .context("couldnt get parent of atomic write path")?;
+    DirBuilder::new().recursive(true).create(parent)?;
+
+    let tmp_path = tmp_path(path)?;
+    let res = write_tmp(&tmp_path, contents.as_ref(), fsync)
+        .and_then(|_| persist(&tmp_path, path, fsync));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/// Renames a fully written (and, if asked, synced) temp file to `path`
+pub(crate) fn persist(tmp_path: &Path, path: &Path, fsync: Fsync) -> anyhow::Result<()> {
+    let parent = path
+        .parent()
+        .context("couldnt get parent of persist path")?;
+    DirBuilder::new().recursive(true).create(parent)?;
+    std::fs::rename(tmp_path, path)?;
+
+    if fsync == Fsync::FileAndDir {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+fn write_tmp(tmp_path: &Path, contents: &[u8], fsync: Fsync) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)?;
+    file.write_all(contents)?;
+    if fsync != Fsync::Never {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// A unique sibling of `path`, unique across both processes and threads
+pub(crate) fn tmp_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .context("missing file name in atomic write path")?
+        .to_string_lossy();
+    let counter = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    Ok(path.with_file_name(format!(
+        ".{}.tmp.{}.{}",
+        file_name,
+        std::process::id(),
+        counter
+    )))
+}
+
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// directories cant be opened for syncing on windows, and renames are durable there anyway
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Advisory lock on a directory, held until dropped. Only cooperates with
+/// other processes that also take the lock, ie other `Cache`s.
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    pub(crate) fn exclusive(dir: &Path) -> anyhow::Result<Self> {
+        let file = open_lock_file(dir)?;
+        file.lock()?;
+        Ok(Self { _file: file })
+    }
+
+    pub(crate) fn shared(dir: &Path) -> anyhow::Result<Self> {
+        let file = open_lock_file(dir)?;
+        file.lock_shared()?;
+        Ok(Self { _file: file })
+    }
+}
+
+fn open_lock_file(dir: &Path) -> anyhow::Result<File> {
+    DirBuilder::new().recursive(true).create(dir)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn atomic_write_replaces_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replaced");
+        write_atomic(&path, "first", Fsync::Never).unwrap();
+        write_atomic(&path, "second", Fsync::FileAndDir).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+
+        let leftover_tmp = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .any(|name| name.starts_with(".replaced.tmp"));
+        assert!(!leftover_tmp);
+    }
+
+    #[test]
+    fn exclusive_lock_blocks_other_handles() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = DirLock::exclusive(dir.path()).unwrap();
+
+        let other = open_lock_file(dir.path()).unwrap();
+        assert!(other.try_lock_shared().is_err());
+
+        drop(lock);
+        assert!(other.try_lock_shared().is_ok());
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::{BufReader, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// How content is stored at rest. Integrity always covers the uncompressed bytes,
+/// so the same value has the same integrity whatever the mode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 3] = [Compression::None, Compression::Zstd, Compression::Gzip];
+
+    /// Added to the content key, so compressed and raw copies of the same value never collide
+    pub(crate) fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zst"),
+            Compression::Gzip => Some("gz"),
+        }
+    }
+
+    pub(crate) fn compress(self, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(self, Vec::new())?;
+        encoder.write_all(content)?;
+        encoder.finish()
+    }
+
+    pub(crate) fn decoder<'a>(
+        self,
+        inner: Box<dyn Read + Send + 'a>,
+    ) -> anyhow::Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            Compression::None => inner,
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(
+                BufReader::new(inner),
+            )?),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(inner)),
+        })
+    }
+}
+
+/// Compresses on the way through to `W` in whichever mode was asked for
+pub(crate) enum Encoder<W: Write> {
+    None(W),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(compression: Compression, inner: W) -> anyhow::Result<Self> {
+        Ok(match compression {
+            Compression::None => Encoder::None(inner),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(inner, 0)?),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+        })
+    }
+
+    /// Writes out anything still buffered and hands back the inner writer
+    pub(crate) fn finish(self) -> anyhow::Result<W> {
+        Ok(match self {
+            Encoder::None(inner) => inner,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::None(inner) => inner.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(inner) => inner.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_every_mode() {
+        let html = "<p>compresses well</p>".repeat(100);
+        for compression in Compression::ALL {
+            let compressed = compression.compress(html.as_bytes()).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < html.len() / 4);
+            }
+
+            let mut decompressed = String::new();
+            compression
+                .decoder(Box::new(compressed.as_slice()))
+                .unwrap()
+                .read_to_string(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, html);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use ssri::{Integrity, IntegrityOpts};
+
+use crate::{compression::Compression, storage::Storage, stream::Reader};
+
+pub fn put(
+    storage: &dyn Storage,
+    content: impl AsRef<[u8]>,
+    hasher: IntegrityOpts,
+    compression: Compression,
+) -> anyhow::Result<(Integrity, PathBuf)> {
+    let integrity = hasher.chain(&content).result();
+    let key = content_key(&integrity, compression)?;
+    if !storage.has_content(&key)? {
+        storage.write_content(&key, &compression.compress(content.as_ref())?)?;
+    }
+    Ok((integrity, storage.content_path(&key)))
+}
+
+/// Reads content back, refusing to return anything that no longer matches its integrity
+pub fn read(
+    storage: &dyn Storage,
+    integrity: &Integrity,
+    compression: Compression,
+) -> anyhow::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    compression
+        .decoder(storage.read_content(&content_key(integrity, compression)?)?)?
+        .read_to_end(&mut content)?;
+    integrity.check(&content)?;
+    Ok(content)
+}
+
+pub fn open<'a>(
+    storage: &'a dyn Storage,
+    integrity: &Integrity,
+    compression: Compression,
+) -> anyhow::Result<Reader<'a>> {
+    let inner = storage.read_content(&content_key(integrity, compression)?)?;
+    Ok(Reader::new(compression.decoder(inner)?, integrity.clone()))
+}
+
+/// How the content for `integrity` was stored, for reads that dont go through the index
+pub fn stored_compression(
+    storage: &dyn Storage,
+    integrity: &Integrity,
+) -> anyhow::Result<Compression> {
+    for compression in Compression::ALL {
+        if storage.has_content(&content_key(integrity, compression)?)? {
+            return Ok(compression);
+        }
+    }
+    Err(anyhow::anyhow!("content not found for {}", integrity))
+}
+
+/// Removes every stored copy of `integrity`, returning whether there were any
+pub fn remove(storage: &dyn Storage, integrity: &Integrity) -> anyhow::Result<bool> {
+    let mut removed = false;
+    for compression in Compression::ALL {
+        removed |= storage.remove_content(&content_key(integrity, compression)?)?;
+    }
+    Ok(removed)
+}
+
+/// algo/ab/cd/rest, plus an extension for compressed copies, with any '/' in the
+/// digest swapped for '_'. Named after the first, ie strongest, hash when the
+/// integrity has several
+pub(crate) fn content_key(
+    integrity: &Integrity,
+    compression: Compression,
+) -> anyhow::Result<PathBuf> {
+    let mut path = PathBuf::new();
+    let hash = integrity
+        .hashes
+        .first()
+        .context("no hashes in integrity")?
+        .to_string();
+    let (algo, rest) = hash
+        .split_once('-')
+        .context("missing '-' in hash in identity string")?;
+    // base64 digests can contain '/', which would otherwise nest or even escape the store
+    let rest = rest.replace('/', "_");
+    let (Some(first), Some(second), Some(rest)) = (rest.get(0..2), rest.get(2..4), rest.get(4..))
+    else {
+        anyhow::bail!("digest too short in identity string");
+    };
+    path.push(algo);
+    path.push(first);
+    path.push(second);
+    path.push(rest);
+    if let Some(extension) = compression.extension() {
+        path.add_extension(extension);
+    }
+
+    Ok(path)
+}
+
+/// The inverse of `content_key`
+pub(crate) fn parse_key(key: &Path) -> anyhow::Result<(Integrity, Compression)> {
+    let extension = key.extension().and_then(|e| e.to_str());
+    let compression = Compression::ALL
+        .into_iter()
+        .find(|c| c.extension().is_some() && c.extension() == extension)
+        .unwrap_or_default();
+    let key = match compression {
+        Compression::None => key.to_path_buf(),
+        _ => key.with_extension(""),
+    };
+
+    let mut parts = key.iter().map(|part| part.to_string_lossy());
+    let algo = parts.next().context("empty content key")?;
+    let digest: String = parts.collect::<String>().replace('_', "/");
+    Ok((format!("{}-{}", algo, digest).parse()?, compression))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{options::CacheOptions, storage::MemoryStorage};
+    use ssri::Algorithm;
+
+    #[test]
+    fn get_path_from_integrity() {
+        let sri = Integrity::from(b"hello");
+        //should be src = sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=
+        let path = content_key(&sri, Compression::None).unwrap();
+        assert_eq!(
+            path.to_str().unwrap(),
+            "sha256/LP/JN/ul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+
+        let path = content_key(&sri, Compression::Zstd).unwrap();
+        assert_eq!(
+            path.to_str().unwrap(),
+            "sha256/LP/JN/ul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=.zst"
+        );
+        assert_eq!(parse_key(&path).unwrap(), (sri, Compression::Zstd));
+
+        // a '/' in the digest stays inside the store and still parses back
+        let sri: Integrity = "sha256-ab/cdef/gh=".parse().unwrap();
+        let path = content_key(&sri, Compression::None).unwrap();
+        assert_eq!(path.to_str().unwrap(), "sha256/ab/_c/def_gh=");
+        assert_eq!(parse_key(&path).unwrap(), (sri, Compression::None));
+    }
+
+    #[test]
+    fn save_data_in_cache() {
+        let storage = MemoryStorage::new();
+        let content = b"hello";
+        let (integrity, path) = put(
+            &storage,
+            content,
+            CacheOptions::default().hasher(),
+            Compression::None,
+        )
+        .unwrap();
+
+        assert_eq!(integrity, Integrity::from(content));
+        assert_eq!(path, content_key(&integrity, Compression::None).unwrap());
+        assert_eq!(
+            read(&storage, &integrity, Compression::None).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn multiple_algorithms() {
+        let storage = MemoryStorage::new();
+        let options = CacheOptions {
+            algorithms: vec![Algorithm::Xxh3, Algorithm::Sha512],
+            ..Default::default()
+        };
+        let (integrity, path) =
+            put(&storage, b"hello", options.hasher(), Compression::None).unwrap();
+
+        assert_eq!(integrity.hashes.len(), 2);
+        // the strongest hash names the content
+        assert_eq!(integrity.pick_algorithm(), Algorithm::Sha512);
+        assert!(path.starts_with("sha512"));
+        assert_eq!(
+            read(&storage, &integrity, Compression::None).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn compressed_content_keeps_its_integrity() {
+        let storage = MemoryStorage::new();
+        let html = "<li>item</li>".repeat(200);
+        let (integrity, path) = put(
+            &storage,
+            &html,
+            CacheOptions::default().hasher(),
+            Compression::Gzip,
+        )
+        .unwrap();
+
+        assert_eq!(integrity, Integrity::from(&html));
+        assert_eq!(path.extension().unwrap(), "gz");
+        assert_eq!(
+            stored_compression(&storage, &integrity).unwrap(),
+            Compression::Gzip
+        );
+        assert_eq!(
+            read(&storage, &integrity, Compression::Gzip).unwrap(),
+            html.as_bytes()
+        );
+    }
+
+    #[test]
+    fn read_rejects_corrupt_content() {
+        let storage = MemoryStorage::new();
+        let integrity = Integrity::from("original");
+        let key = content_key(&integrity, Compression::None).unwrap();
+        storage.write_content(&key, b"tampered").unwrap();
+
+        assert!(read(&storage, &integrity, Compression::None).is_err());
+    }
+}
@@ -1,46 +1,189 @@
 use std::{
-    fs::DirBuilder,
-    path::{Path, PathBuf},
+    collections::HashSet,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
-use hex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use ssri::Integrity;
+
+use crate::{compression::Compression, storage::Storage};
+
+/// One line of a bucket. Buckets are append only, so a key's history is every
+/// entry for it in order and the last one is current. Removing a key appends a
+/// tombstone, an entry with no integrity or path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// the serialized key, before its value path was set
+    pub key: String,
+    pub integrity: Option<String>,
+    pub path: Option<PathBuf>,
+    /// milliseconds since the unix epoch
+    pub time: u64,
+    /// uncompressed size in bytes
+    pub size: usize,
+    /// how the content is stored at rest
+    #[serde(default)]
+    pub compression: Compression,
+    pub metadata: serde_json::Value,
+}
+
+impl Entry {
+    pub fn integrity(&self) -> anyhow::Result<Integrity> {
+        Ok(self
+            .integrity
+            .as_ref()
+            .context("entry is a removal tombstone")?
+            .parse()?)
+    }
+    pub fn is_removed(&self) -> bool {
+        self.integrity.is_none()
+    }
+}
 
 pub fn add_index(
-    index_base_path: &Path,
+    storage: &dyn Storage,
     index: &mut (impl Serialize + Index),
     cache_path: PathBuf,
-) -> anyhow::Result<PathBuf> {
-    let index_path = get_key_path(index_base_path, index)?;
-    let parent = index_path
-        .parent()
-        .context("couldnt get parent in 'key' path")?;
-    DirBuilder::new().recursive(true).create(parent)?;
-
-    // need to do this after hashing for the index path
+    integrity: &Integrity,
+    size: usize,
+    compression: Compression,
+    metadata: serde_json::Value,
+) -> anyhow::Result<()> {
+    let key = serde_json::to_string(index)?;
+
+    // need to do this after serializing for the bucket
     // becasue when retrieve will hash the non-cache-path-ed key because we are looking to retrieve
-    // the path to the cached item which we wont have. So add the path now, reserialize, and then
-    // write key to index
+    // the path to the cached item which we wont have. So add the path now, and hand it back to
+    // the caller in their key
+
+    index.set_value_hash_path(cache_path.clone());
+
+    let entry = Entry {
+        key,
+        integrity: Some(integrity.to_string()),
+        path: Some(cache_path),
+        time: now_millis()?,
+        size,
+        compression,
+        metadata,
+    };
+    append_entry(storage, &entry)
+}
+
+pub(crate) fn append_entry(storage: &dyn Storage, entry: &Entry) -> anyhow::Result<()> {
+    storage.append_index(&bucket(&entry.key), &serialize_entry(entry)?)
+}
+
+/// Appends a tombstone so `key` stops resolving, keeping its history intact
+pub(crate) fn remove_entry(storage: &dyn Storage, key: &str) -> anyhow::Result<()> {
+    let entry = Entry {
+        key: key.to_string(),
+        integrity: None,
+        path: None,
+        time: now_millis()?,
+        size: 0,
+        compression: Compression::None,
+        metadata: serde_json::Value::Null,
+    };
+    append_entry(storage, &entry)
+}
+
+/// Latest intact entry for `key` in its bucket, if there is one and it wasnt removed
+pub(crate) fn find_entry(storage: &dyn Storage, key: &str) -> anyhow::Result<Option<Entry>> {
+    Ok(key_history(storage, key)?
+        .pop()
+        .filter(|entry| !entry.is_removed()))
+}
+
+/// Every intact entry for `key`, oldest first
+pub(crate) fn key_history(storage: &dyn Storage, key: &str) -> anyhow::Result<Vec<Entry>> {
+    let entries = read_bucket(storage, &bucket(key))?;
+    Ok(entries.into_iter().filter(|e| e.key == key).collect())
+}
+
+fn read_bucket(storage: &dyn Storage, bucket: &str) -> anyhow::Result<Vec<Entry>> {
+    let raw = storage.read_index(bucket)?.unwrap_or_default();
+    Ok(raw.lines().filter_map(parse_entry).collect())
+}
+
+/// Appends the intact lines of `raw` that `bucket` doesnt already have, returning how many
+pub(crate) fn merge_bucket(
+    storage: &dyn Storage,
+    bucket: &str,
+    raw: &str,
+) -> anyhow::Result<usize> {
+    let existing = storage.read_index(bucket)?.unwrap_or_default();
+    let existing: HashSet<&str> = existing.lines().collect();
+
+    let mut merged = 0;
+    for line in raw.lines() {
+        if parse_entry(line).is_some() && !existing.contains(line) {
+            storage.append_index(bucket, &format!("\n{}", line))?;
+            merged += 1;
+        }
+    }
+    Ok(merged)
+}
+
+/// Current entry of every live key in the index, one bucket at a time
+pub(crate) fn ls(
+    storage: &dyn Storage,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Entry>> + '_> {
+    let buckets = storage.buckets()?;
+    Ok(buckets
+        .into_iter()
+        .flat_map(move |bucket| match read_bucket(storage, &bucket) {
+            Ok(entries) => latest_per_key(entries).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        }))
+}
 
-    index.set_value_hash_path(cache_path);
-    let serialized = serde_json::to_string(index)?;
-    std::fs::write(&index_path, serialized)?;
-    Ok(index_path)
+fn latest_per_key(entries: Vec<Entry>) -> Vec<Entry> {
+    let mut latest: Vec<Entry> = Vec::new();
+    for entry in entries {
+        match latest.iter_mut().find(|e| e.key == entry.key) {
+            Some(existing) => *existing = entry,
+            None => latest.push(entry),
+        }
+    }
+    latest.retain(|entry| !entry.is_removed());
+    latest
 }
-pub(crate) fn get_key_path(
-    index_base_path: &Path,
-    index: &(impl Serialize + Index),
-) -> anyhow::Result<PathBuf> {
-    let mut index_path = PathBuf::from(index_base_path);
-    let serialized = serde_json::to_string(index)?;
-    let hash: String = hex::encode(Sha256::digest(&serialized).to_vec());
-    index_path.push(&hash[0..2]);
-    index_path.push(&hash[2..4]);
-    index_path.push(&hash[4..]);
 
-    Ok(index_path)
+fn serialize_entry(entry: &Entry) -> anyhow::Result<String> {
+    let json = serde_json::to_string(entry)?;
+    // leading newline rather than trailing, so a line appended after a torn one still starts fresh
+    Ok(format!("\n{}\t{}", hash_hex(&json), json))
+}
+
+/// None for lines that are torn, tampered with or otherwise unreadable
+fn parse_entry(line: &str) -> Option<Entry> {
+    let (hash, json) = line.split_once('\t')?;
+    if hash != hash_hex(json) {
+        return None;
+    }
+    serde_json::from_str(json).ok()
+}
+
+/// The bucket a serialized key lives in
+pub(crate) fn bucket(key: &str) -> String {
+    hash_hex(key)
+}
+
+/// Whether a name could have come from `bucket`, anything else isnt safe to turn into a path
+pub(crate) fn is_bucket(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn hash_hex(data: &str) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn now_millis() -> anyhow::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
 }
 
 pub trait Index {
@@ -51,41 +194,113 @@ pub trait Index {
 #[cfg(test)]
 mod test {
     use super::*;
-    use serde::{Deserialize, Serialize};
+    use crate::{storage::MemoryStorage, testing::Key};
 
     #[test]
     fn add_index_test() {
-        let index_path = PathBuf::from("test-index");
+        let storage = MemoryStorage::new();
 
-        #[derive(Serialize, Deserialize)]
-        struct Key {
-            data: String,
-            inner_hash_path: Option<PathBuf>,
-        }
+        let mut test_index = Key::new("test_add_index");
+        let key = serde_json::to_string(&test_index).unwrap();
+
+        add_index(
+            &storage,
+            &mut test_index,
+            "this is a fake path".into(),
+            &Integrity::from("fake"),
+            4,
+            Compression::None,
+            serde_json::json!({ "source": "test" }),
+        )
+        .unwrap();
+
+        assert_eq!(test_index.value, Some("this is a fake path".into()));
 
-        let mut test_index = Key {
-            data: "test_add_index".to_string(),
-            inner_hash_path: None,
-        };
-        impl Index for Key {
-            fn set_value_hash_path(&mut self, path: PathBuf) {
-                self.inner_hash_path = Some(path);
-            }
-
-            fn get_value_hash_path(&self) -> anyhow::Result<PathBuf> {
-                unimplemented!()
-            }
+        let entry = find_entry(&storage, &key).unwrap().unwrap();
+        assert_eq!(storage.buckets().unwrap(), vec![bucket(&key)]);
+        assert_eq!(entry.key, key);
+        assert_eq!(entry.path, Some("this is a fake path".into()));
+        assert_eq!(entry.integrity().unwrap(), Integrity::from("fake"));
+        assert_eq!(entry.size, 4);
+        assert_eq!(entry.metadata["source"], "test");
+    }
+
+    #[test]
+    fn latest_valid_entry_wins() {
+        let storage = MemoryStorage::new();
+        let key = serde_json::to_string(&Key::new("latest_valid_entry_wins")).unwrap();
+
+        for (i, data) in ["first", "second"].iter().enumerate() {
+            let mut test_index = Key::new("latest_valid_entry_wins");
+            add_index(
+                &storage,
+                &mut test_index,
+                data.into(),
+                &Integrity::from(data),
+                i,
+                Compression::None,
+                serde_json::Value::Null,
+            )
+            .unwrap();
         }
 
-        let path = add_index(
-            index_path.as_path(),
+        // a line whose checksum doesnt match and a torn write are both ignored
+        let raw = storage.read_index(&bucket(&key)).unwrap().unwrap();
+        let tampered = raw.lines().last().unwrap().replace("second", "third");
+        storage
+            .append_index(&bucket(&key), &format!("\n{tampered}"))
+            .unwrap();
+        storage
+            .append_index(&bucket(&key), "\n0123\t{\"key\":")
+            .unwrap();
+
+        let history = key_history(&storage, &key).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].path, Some("first".into()));
+
+        let entry = find_entry(&storage, &key).unwrap().unwrap();
+        assert_eq!(entry.path, Some("second".into()));
+
+        // and the next append after a torn line is still readable
+        let mut test_index = Key::new("latest_valid_entry_wins");
+        add_index(
+            &storage,
             &mut test_index,
-            "this is a fake path".into(),
+            "fourth".into(),
+            &Integrity::from("fourth"),
+            6,
+            Compression::None,
+            serde_json::Value::Null,
+        )
+        .unwrap();
+        let entry = find_entry(&storage, &key).unwrap().unwrap();
+        assert_eq!(entry.path, Some("fourth".into()));
+    }
+
+    #[test]
+    fn removed_entries_are_hidden_but_kept_in_history() {
+        let storage = MemoryStorage::new();
+
+        let mut test_index = Key::new("removed_entries");
+        let key = serde_json::to_string(&test_index).unwrap();
+        add_index(
+            &storage,
+            &mut test_index,
+            "path".into(),
+            &Integrity::from("removed"),
+            7,
+            Compression::None,
+            serde_json::Value::Null,
         )
         .unwrap();
+        assert_eq!(ls(&storage).unwrap().count(), 1);
 
-        let contents = std::fs::read_to_string(path).unwrap();
+        remove_entry(&storage, &key).unwrap();
 
-        assert_eq!(contents, serde_json::to_string(&test_index).unwrap());
+        assert!(find_entry(&storage, &key).unwrap().is_none());
+        assert_eq!(ls(&storage).unwrap().count(), 0);
+        let history = key_history(&storage, &key).unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[1].is_removed());
     }
 }
@@ -1,129 +1,386 @@
-mod cache;
+extern crate self as cache;
+
+mod archive;
+mod atomic;
+mod compression;
+mod content;
 mod index;
+mod options;
+mod storage;
+mod stream;
+#[cfg(test)]
+mod testing;
+
+use std::{collections::HashSet, path::PathBuf};
 
-use std::path::PathBuf;
+use anyhow::Context;
+use serde::Serialize;
+pub use ssri::{Algorithm, Integrity};
 
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
+#[cfg(feature = "derive")]
+pub use cache_derive::Index;
+
+pub use crate::archive::ImportSummary;
+pub use crate::compression::Compression;
+pub use crate::index::{Entry, Index};
+use crate::index::{add_index, find_entry, key_history, remove_entry};
+pub use crate::options::{CacheOptions, Fsync};
+pub use crate::storage::{FsStorage, MemoryStorage, StagedContent, Storage, TieredStorage};
+pub use crate::stream::{Reader, Writer};
+
+/// Where the browser keeps its shared cache: `$BROWSER_RUST_CACHE_DIR` if set,
+/// otherwise `browser_rust` in the platform cache directory
+pub fn default_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("BROWSER_RUST_CACHE_DIR") {
+        return Some(dir.into());
+    }
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("browser_rust"))
+}
 
-pub use crate::index::Index;
-use crate::index::{add_index, get_key_path};
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow;
+}
 
+/// Content addressed cache over a pluggable `Storage`. The filesystem backend is
+/// safe to share between several processes.
 pub struct Cache {
-    index_path: PathBuf,
-    cache_path: PathBuf,
+    storage: Box<dyn Storage>,
+    options: CacheOptions,
 }
 
 impl Cache {
+    /// A cache on disk, with its index and content in the two given directories
     pub fn new(index_path: PathBuf, cache_path: PathBuf) -> Self {
+        Self::with_options(index_path, cache_path, CacheOptions::default())
+    }
+    pub fn with_options(index_path: PathBuf, cache_path: PathBuf, options: CacheOptions) -> Self {
+        let storage = FsStorage::new(index_path, cache_path, options.fsync);
+        Self::with_storage(storage, options)
+    }
+    /// A cache on any backend. `options.fsync` only applies to backends built by `Cache` itself
+    pub fn with_storage(storage: impl Storage + 'static, options: CacheOptions) -> Self {
         Self {
-            index_path,
-            cache_path,
+            storage: Box::new(storage),
+            options,
         }
     }
+    /// A cache on disk in the `index` and `content` subdirectories of `dir`
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self::new(dir.join("index"), dir.join("content"))
+    }
+    pub fn in_memory() -> Self {
+        Self::with_storage(MemoryStorage::new(), CacheOptions::default())
+    }
+    pub fn options(&self) -> &CacheOptions {
+        &self.options
+    }
+    pub fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
     pub fn save(
         &self,
         key: &mut (impl Serialize + Index),
         value: impl AsRef<[u8]>,
-    ) -> anyhow::Result<PathBuf> {
-        let cache_path = cache::put(&self.cache_path, value)?;
+    ) -> anyhow::Result<Integrity> {
+        self.save_with_metadata(key, value, serde_json::Value::Null)
+    }
+    /// Like `save`, but stores arbitrary `metadata` alongside the index entry
+    pub fn save_with_metadata(
+        &self,
+        key: &mut (impl Serialize + Index),
+        value: impl AsRef<[u8]>,
+        metadata: impl Serialize,
+    ) -> anyhow::Result<Integrity> {
+        let size = value.as_ref().len();
+        let compression = self.options.compression;
+        let (integrity, cache_path) =
+            content::put(&*self.storage, value, self.options.hasher(), compression)?;
 
-        add_index(&self.index_path, key, cache_path)
+        add_index(
+            &*self.storage,
+            key,
+            cache_path,
+            &integrity,
+            size,
+            compression,
+            serde_json::to_value(metadata)?,
+        )?;
+        Ok(integrity)
     }
     pub fn get<T>(&self, key: &T) -> anyhow::Result<Vec<u8>>
     where
-        T: Serialize + Index + DeserializeOwned,
+        T: Serialize + Index,
     {
-        let key_path = get_key_path(&self.index_path, key)?;
+        let entry = self.entry(key)?.context("key not found in cache index")?;
 
-        let key = std::fs::read_to_string(key_path)?;
-        let contents: T = serde_json::from_str(&key)?;
+        content::read(&*self.storage, &entry.integrity()?, entry.compression)
+    }
+    /// Reads content straight from the content store, bypassing the index
+    pub fn get_by_integrity(&self, integrity: &Integrity) -> anyhow::Result<Vec<u8>> {
+        let compression = content::stored_compression(&*self.storage, integrity)?;
+        content::read(&*self.storage, integrity, compression)
+    }
+    /// Starts a streamed save of a value under `key`, see `Writer`
+    pub fn writer<'a, T>(&'a self, key: &'a mut T) -> anyhow::Result<Writer<'a, T>>
+    where
+        T: Serialize + Index,
+    {
+        Writer::new(self, key)
+    }
+    /// Streams the value for `key` back out, verifying it as it is read
+    pub fn reader<T>(&self, key: &T) -> anyhow::Result<Reader<'_>>
+    where
+        T: Serialize + Index,
+    {
+        let entry = self.entry(key)?.context("key not found in cache index")?;
 
-        let cache_path = contents.get_value_hash_path()?;
-        Ok(std::fs::read(cache_path)?)
+        content::open(&*self.storage, &entry.integrity()?, entry.compression)
+    }
+    pub fn reader_by_integrity(&self, integrity: &Integrity) -> anyhow::Result<Reader<'_>> {
+        let compression = content::stored_compression(&*self.storage, integrity)?;
+        content::open(&*self.storage, integrity, compression)
+    }
+    pub fn contains<T>(&self, key: &T) -> anyhow::Result<bool>
+    where
+        T: Serialize + Index,
+    {
+        Ok(self.entry(key)?.is_some())
+    }
+    /// Removes `key` from the index. The content stays, since other keys may share it;
+    /// use `remove_content` to drop that too
+    pub fn remove<T>(&self, key: &T) -> anyhow::Result<()>
+    where
+        T: Serialize + Index,
+    {
+        remove_entry(&*self.storage, &serde_json::to_string(key)?)
+    }
+    /// Deletes the content for `integrity`. Index entries still pointing at it will fail to `get`
+    pub fn remove_content(&self, integrity: &Integrity) -> anyhow::Result<bool> {
+        content::remove(&*self.storage, integrity)
+    }
+    /// Deletes every index entry and all content
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.storage.clear()
+    }
+    /// Current entry of every key in the index, in no particular order
+    pub fn entries(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Entry>> + '_> {
+        index::ls(&*self.storage)
+    }
+    /// Entries whose content is missing or no longer matches its integrity
+    pub fn verify(&self) -> anyhow::Result<Vec<Entry>> {
+        let mut broken = Vec::new();
+        for entry in self.entries()? {
+            let entry = entry?;
+            let intact = entry
+                .integrity()
+                .and_then(|integrity| content::read(&*self.storage, &integrity, entry.compression));
+            if intact.is_err() {
+                broken.push(entry);
+            }
+        }
+        Ok(broken)
+    }
+    /// Removes content no live entry points at, returning how many pieces were removed.
+    /// Content written by another process but not yet indexed can be caught by this,
+    /// so dont run it while other writers are active
+    pub fn gc(&self) -> anyhow::Result<usize> {
+        let mut live = HashSet::new();
+        for entry in self.entries()? {
+            let entry = entry?;
+            live.insert(content::content_key(
+                &entry.integrity()?,
+                entry.compression,
+            )?);
+        }
+
+        let mut removed = 0;
+        for key in self.storage.content_keys()? {
+            if !live.contains(&key) && self.storage.remove_content(&key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+    /// The current index entry for `key`, with its integrity, size and metadata
+    pub fn entry<T>(&self, key: &T) -> anyhow::Result<Option<Entry>>
+    where
+        T: Serialize + Index,
+    {
+        find_entry(&*self.storage, &serde_json::to_string(key)?)
+    }
+    /// Every entry ever saved for `key`, oldest first
+    pub fn history<T>(&self, key: &T) -> anyhow::Result<Vec<Entry>>
+    where
+        T: Serialize + Index,
+    {
+        key_history(&*self.storage, &serde_json::to_string(key)?)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use crate::{Cache, testing::Key};
+
+    #[test]
+    fn save_in_cache() {
+        let cache = Cache::in_memory();
+
+        let mut key = Key::new("save_in_cache");
+
+        cache.save(&mut key, "hello").unwrap();
 
-    use anyhow::Context;
-    use serde::{Deserialize, Serialize};
+        let Some(value_path) = key.value.clone() else {
+            panic!("missing value path in key")
+        };
+
+        let entry = cache.entry(&Key::new("save_in_cache")).unwrap().unwrap();
+        assert_eq!(entry.path, Some(value_path));
+        assert_eq!(entry.size, 5);
 
-    use crate::{Cache, Index};
+        let value = cache.get_by_integrity(&entry.integrity().unwrap()).unwrap();
 
+        assert_eq!(b"hello", value.as_slice());
+    }
     #[test]
-    fn save_in_cache() {
-        let cache = Cache::new("test-index".into(), "test-cache".into());
+    fn retrieve_from_cache() {
+        let cache = Cache::in_memory();
 
-        #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-        struct Key {
-            inner_hash_path: Option<PathBuf>,
-        }
+        let mut key = Key::new("retrieve_from_cache");
 
-        impl Index for Key {
-            fn set_value_hash_path(&mut self, path: PathBuf) {
-                self.inner_hash_path = Some(path);
-            }
+        let _ = cache.save(&mut key, "hello from the other side").unwrap();
+        let new_key = Key::new("retrieve_from_cache");
+
+        let res = cache.get(&new_key).unwrap();
+
+        assert_eq!(res, b"hello from the other side")
+    }
+
+    // the one test that really needs directories on disk, shared by several handles
+    #[test]
+    fn concurrent_writers_same_key() {
+        let key = Key::new("concurrent_writers_same_key");
 
-            fn get_value_hash_path(&self) -> anyhow::Result<PathBuf> {
-                unimplemented!()
+        let dir = tempfile::tempdir().unwrap();
+        let (index, content) = (dir.path().join("index"), dir.path().join("cache"));
+        let values: Vec<String> = (0..8)
+            .map(|i| format!("writer {i} ").repeat(4096))
+            .collect();
+        std::thread::scope(|s| {
+            for value in &values {
+                let mut key = key.clone();
+                let (index, content) = (index.clone(), content.clone());
+                s.spawn(move || {
+                    let cache = Cache::new(index, content);
+                    cache.save(&mut key, value).unwrap();
+                });
             }
-        }
-        let mut key = Key {
-            inner_hash_path: None,
-        };
+        });
+
+        let cache = Cache::new(index, content);
+        let res = String::from_utf8(cache.get(&key).unwrap()).unwrap();
+        assert!(values.contains(&res));
+    }
 
-        let key_path = cache.save(&mut key, "hello").unwrap();
+    #[test]
+    fn remove_list_and_clear() {
+        let cache = Cache::in_memory();
 
-        let key_raw = std::fs::read_to_string(&key_path).unwrap();
+        let key = Key::new;
 
-        let key_deser: Key = serde_json::from_str(&key_raw).unwrap();
+        cache.save(&mut key("one"), "first value").unwrap();
+        cache
+            .save_with_metadata(&mut key("two"), "second value", "some metadata")
+            .unwrap();
+        assert!(cache.contains(&Key::new("one")).unwrap());
 
-        assert_eq!(key_deser, key);
+        let mut listed: Vec<_> = cache.entries().unwrap().map(|e| e.unwrap()).collect();
+        listed.sort_by_key(|e| e.size);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[1].metadata, "some metadata");
 
-        let Some(value_path) = key_deser.inner_hash_path else {
-            panic!("missing value path in deser")
-        };
+        cache.remove(&Key::new("one")).unwrap();
+        assert!(!cache.contains(&Key::new("one")).unwrap());
+        assert!(cache.get(&Key::new("one")).is_err());
+        assert_eq!(cache.entries().unwrap().count(), 1);
 
-        let value = std::fs::read_to_string(value_path).unwrap();
+        let integrity = cache
+            .entry(&Key::new("two"))
+            .unwrap()
+            .unwrap()
+            .integrity()
+            .unwrap();
+        assert_eq!(cache.get_by_integrity(&integrity).unwrap(), b"second value");
+        assert!(cache.remove_content(&integrity).unwrap());
+        assert!(cache.get(&Key::new("two")).is_err());
 
-        assert_eq!("hello", value);
+        cache.clear().unwrap();
+        assert_eq!(cache.entries().unwrap().count(), 0);
     }
+
+    #[cfg(feature = "derive")]
     #[test]
-    fn retrieve_from_cache() {
-        let cache = Cache::new("test-index".into(), "test-cache".into());
+    fn derived_index() {
+        use std::path::PathBuf;
 
-        #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-        struct Key {
-            data: String,
-            inner_hash_path: Option<PathBuf>,
-        }
+        use serde::{Deserialize, Serialize};
 
-        impl Index for Key {
-            fn set_value_hash_path(&mut self, path: PathBuf) {
-                self.inner_hash_path = Some(path);
-            }
+        use crate::Index;
 
-            fn get_value_hash_path(&self) -> anyhow::Result<PathBuf> {
-                self.inner_hash_path
-                    .clone()
-                    .context("missing inner hash path")
-            }
+        // generic, unlike the shared test key
+        #[derive(Serialize, Deserialize, crate::Index)]
+        struct Key<T> {
+            data: T,
+            #[index(value_path)]
+            value: Option<PathBuf>,
         }
+
         let mut key = Key {
-            data: "retrieve_from_cache".into(),
-            inner_hash_path: None,
+            data: 42,
+            value: None,
         };
+        assert!(key.get_value_hash_path().is_err());
 
-        let _ = cache.save(&mut key, "hello from the other side").unwrap();
-        let new_key = Key {
-            data: "retrieve_from_cache".into(),
-            inner_hash_path: None,
+        let cache = Cache::in_memory();
+        cache.save(&mut key, "derived").unwrap();
+        assert!(key.get_value_hash_path().unwrap().starts_with("sha256"));
+
+        let lookup = Key {
+            data: 42,
+            value: None,
         };
+        assert_eq!(cache.get(&lookup).unwrap(), b"derived");
+    }
 
-        let res = cache.get(&new_key).unwrap();
+    #[test]
+    fn compressed_cache() {
+        let key = || Key::new("http://example.com");
+        let html = "<html><body>hello</body></html>".repeat(50);
 
-        assert_eq!(res, b"hello from the other side")
+        let cache = Cache::with_storage(
+            crate::MemoryStorage::new(),
+            crate::CacheOptions {
+                compression: crate::Compression::Zstd,
+                ..Default::default()
+            },
+        );
+        let integrity = cache.save(&mut key(), &html).unwrap();
+
+        let entry = cache.entry(&key()).unwrap().unwrap();
+        assert_eq!(entry.compression, crate::Compression::Zstd);
+        assert_eq!(entry.size, html.len());
+        assert_eq!(integrity, crate::Integrity::from(&html));
+
+        assert_eq!(cache.get(&key()).unwrap(), html.as_bytes());
+        assert_eq!(cache.get_by_integrity(&integrity).unwrap(), html.as_bytes());
+
+        let mut streamed = String::new();
+        std::io::Read::read_to_string(&mut cache.reader(&key()).unwrap(), &mut streamed).unwrap();
+        assert_eq!(streamed, html);
     }
 }
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    env::args,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    process::exit,
+};
+
+use anyhow::Context;
+use cache::{Cache, Index};
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+const USAGE: &str = "\
+usage: cache [--dir DIR] <command>
+
+commands:
+  ls                 list every key, with its size and integrity
+  get <key>          write the value for a key, as printed by ls, to stdout
+  rm <key>           remove a key from the index
+  rm --all           remove every key and all content
+  verify             check every entry's content against its integrity
+  gc                 delete content that no key points at
+  stats              entry count, total size and dedup savings
+  export <file.tar>  write the whole cache to a tar archive
+  import <file.tar>  merge a tar archive into the cache
+
+DIR defaults to $BROWSER_RUST_CACHE_DIR, then the platform cache directory";
+
+/// A key exactly as it was serialized into the index, so any key type can be looked up
+#[derive(Serialize)]
+#[serde(transparent)]
+struct RawKey(Box<RawValue>);
+
+impl Index for RawKey {
+    fn set_value_hash_path(&mut self, _path: PathBuf) {}
+
+    fn get_value_hash_path(&self) -> anyhow::Result<PathBuf> {
+        Err(anyhow::anyhow!("raw keys dont carry a value path"))
+    }
+}
+
+impl RawKey {
+    fn parse(key: &str) -> anyhow::Result<Self> {
+        Ok(Self(
+            RawValue::from_string(key.to_string()).context("key must be json, as printed by ls")?,
+        ))
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    match run() {
+        // `cache ls | head` closing stdout early isnt a failure
+        Err(err)
+            if err
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::BrokenPipe) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let mut args: Vec<String> = args().skip(1).collect();
+
+    let dir = match args.iter().position(|arg| arg == "--dir") {
+        Some(i) => {
+            let dir = args.get(i + 1).context("--dir needs a directory")?.into();
+            args.drain(i..=i + 1);
+            dir
+        }
+        None => cache::default_dir().context("couldnt find a cache directory, pass --dir")?,
+    };
+    let cache = Cache::open(dir);
+
+    let Some(command) = args.first() else {
+        eprintln!("{}", USAGE);
+        exit(1);
+    };
+    let arg = args.get(1).map(String::as_str);
+
+    match (command.as_str(), arg) {
+        ("ls", None) => ls(&cache),
+        ("get", Some(key)) => get(&cache, key),
+        ("rm", Some("--all")) => cache.clear(),
+        ("rm", Some(key)) => cache.remove(&RawKey::parse(key)?),
+        ("verify", None) => verify(&cache),
+        ("gc", None) => {
+            println!("removed {} unreferenced pieces of content", cache.gc()?);
+            Ok(())
+        }
+        ("stats", None) => stats(&cache),
+        ("export", Some(path)) => {
+            let file = BufWriter::new(File::create(path)?);
+            cache.export(file)
+        }
+        ("import", Some(path)) => {
+            let file = BufReader::new(File::open(path)?);
+            let summary = cache.import(file)?;
+            println!(
+                "imported {} index entries and {} pieces of content",
+                summary.entries, summary.contents
+            );
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    }
+}
+
+fn ls(cache: &Cache) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    for entry in cache.entries()? {
+        let entry = entry?;
+        writeln!(
+            stdout,
+            "{}\t{}\t{}",
+            entry.key,
+            entry.size,
+            entry.integrity.unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads the whole value first, a streamed read only finds corruption at the end, after the
+/// bad bytes are already written
+fn get(cache: &Cache, key: &str) -> anyhow::Result<()> {
+    let value = cache.get(&RawKey::parse(key)?)?;
+    std::io::stdout().lock().write_all(&value)?;
+    Ok(())
+}
+
+fn verify(cache: &Cache) -> anyhow::Result<()> {
+    let broken = cache.verify()?;
+    for entry in &broken {
+        println!(
+            "{}\t{}",
+            entry.key,
+            entry.integrity.as_deref().unwrap_or_default()
+        );
+    }
+    if !broken.is_empty() {
+        eprintln!("{} entries have missing or corrupt content", broken.len());
+        exit(1);
+    }
+    Ok(())
+}
+
+fn stats(cache: &Cache) -> anyhow::Result<()> {
+    let mut entries = 0;
+    let mut total_size = 0;
+    let mut unique: HashMap<String, usize> = HashMap::new();
+    for entry in cache.entries()? {
+        let entry = entry?;
+        entries += 1;
+        total_size += entry.size;
+        if let Some(integrity) = entry.integrity {
+            unique.insert(integrity, entry.size);
+        }
+    }
+    let unique_size: usize = unique.values().sum();
+
+    println!("entries:        {}", entries);
+    println!("content pieces: {}", cache.storage().content_keys()?.len());
+    println!("total size:     {} bytes", total_size);
+    println!("unique size:    {} bytes", unique_size);
+    println!("dedup savings:  {} bytes", total_size - unique_size);
+    Ok(())
+}
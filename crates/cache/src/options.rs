@@ -0,0 +1,48 @@
+use ssri::{Algorithm, IntegrityOpts};
+
+use crate::compression::Compression;
+
+/// How hard a write tries to survive a crash before returning
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fsync {
+    /// leave flushing to the OS. A crash can lose the write, but never leaves
+    /// a partial file behind the final name
+    Never,
+    /// flush the file contents before renaming it into place
+    #[default]
+    File,
+    /// also flush the containing directory, so the rename itself is durable
+    FileAndDir,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    pub fsync: Fsync,
+    /// Hashes to compute for new content. All of them end up in the integrity
+    /// string, and the strongest one decides where the content is stored.
+    /// Empty means sha256.
+    pub algorithms: Vec<Algorithm>,
+    /// How new content is stored. Existing content is read back however it was written
+    pub compression: Compression,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            fsync: Fsync::default(),
+            algorithms: vec![Algorithm::Sha256],
+            compression: Compression::None,
+        }
+    }
+}
+
+impl CacheOptions {
+    pub(crate) fn hasher(&self) -> IntegrityOpts {
+        if self.algorithms.is_empty() {
+            return IntegrityOpts::new().algorithm(Algorithm::Sha256);
+        }
+        self.algorithms
+            .iter()
+            .fold(IntegrityOpts::new(), |opts, algo| opts.algorithm(*algo))
+    }
+}
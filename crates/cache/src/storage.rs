@@ -0,0 +1,44 @@
+mod fs;
+mod memory;
+mod tiered;
+
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+pub use self::fs::FsStorage;
+pub use self::memory::MemoryStorage;
+pub use self::tiered::TieredStorage;
+
+/// Where a `Cache` keeps its content and index. Content lives under relative keys
+/// derived from its integrity (algo/ab/cd/rest) and never changes once written;
+/// the index is a set of append only buckets, named by the hex hash of the keys
+/// they hold.
+pub trait Storage: Send + Sync {
+    /// The location of the content under `key`, handed to keys via `Index::set_value_hash_path`
+    fn content_path(&self, key: &Path) -> PathBuf;
+    fn has_content(&self, key: &Path) -> anyhow::Result<bool>;
+    fn write_content(&self, key: &Path, content: &[u8]) -> anyhow::Result<()>;
+    /// Somewhere to stream content whose key isnt known until it has all been written
+    fn stage_content(&self) -> anyhow::Result<Box<dyn StagedContent + '_>>;
+    /// The raw stored bytes; verifying them is up to the caller
+    fn read_content(&self, key: &Path) -> anyhow::Result<Box<dyn Read + Send + '_>>;
+    /// Returns whether there was anything to remove
+    fn remove_content(&self, key: &Path) -> anyhow::Result<bool>;
+    /// Key of every piece of committed content
+    fn content_keys(&self) -> anyhow::Result<Vec<PathBuf>>;
+
+    fn append_index(&self, bucket: &str, line: &str) -> anyhow::Result<()>;
+    /// The whole bucket, or None if nothing was ever appended to it
+    fn read_index(&self, bucket: &str) -> anyhow::Result<Option<String>>;
+    fn buckets(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Removes all content and every bucket
+    fn clear(&self) -> anyhow::Result<()>;
+}
+
+/// Content being streamed into a `Storage`. Dropping it without committing discards it.
+pub trait StagedContent: Write + Send {
+    fn commit(self: Box<Self>, key: &Path) -> anyhow::Result<()>;
+}
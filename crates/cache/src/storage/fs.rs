@@ -0,0 +1,303 @@
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use super::{StagedContent, Storage};
+use crate::{
+    atomic::{DirLock, persist, sync_dir, tmp_path, write_atomic},
+    options::Fsync,
+};
+
+/// Keeps the index and content in two directories on disk. Several processes can
+/// share the same directories: content lands atomically and the index is guarded
+/// by an advisory lock.
+pub struct FsStorage {
+    index_path: PathBuf,
+    cache_path: PathBuf,
+    fsync: Fsync,
+}
+
+impl FsStorage {
+    pub fn new(index_path: PathBuf, cache_path: PathBuf, fsync: Fsync) -> Self {
+        Self {
+            index_path,
+            cache_path,
+            fsync,
+        }
+    }
+
+    fn bucket_path(&self, bucket: &str) -> PathBuf {
+        let mut path = self.index_path.clone();
+        path.push(&bucket[0..2]);
+        path.push(&bucket[2..4]);
+        path.push(&bucket[4..]);
+        path
+    }
+
+    /// Where in-progress streamed writes live. Inside the store so the final rename stays on one filesystem
+    fn tmp_dir(&self) -> PathBuf {
+        self.cache_path.join("tmp")
+    }
+}
+
+impl Storage for FsStorage {
+    fn content_path(&self, key: &Path) -> PathBuf {
+        self.cache_path.join(key)
+    }
+
+    fn has_content(&self, key: &Path) -> anyhow::Result<bool> {
+        Ok(self.content_path(key).exists())
+    }
+
+    fn write_content(&self, key: &Path, content: &[u8]) -> anyhow::Result<()> {
+        let path = self.content_path(key);
+        // content addressed, so if its already there another writer beat us to the same bytes
+        if !path.exists() {
+            write_atomic(&path, content, self.fsync)?;
+        }
+        Ok(())
+    }
+
+    fn stage_content(&self) -> anyhow::Result<Box<dyn StagedContent + '_>> {
+        let tmp_dir = self.tmp_dir();
+        DirBuilder::new().recursive(true).create(&tmp_dir)?;
+        let tmp_path = tmp_path(&tmp_dir.join("stream"))?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+
+        Ok(Box::new(FsStagedContent {
+            storage: self,
+            file: Some(file),
+            tmp_path,
+        }))
+    }
+
+    fn read_content(&self, key: &Path) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        Ok(Box::new(File::open(self.content_path(key))?))
+    }
+
+    fn remove_content(&self, key: &Path) -> anyhow::Result<bool> {
+        match std::fs::remove_file(self.content_path(key)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn content_keys(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut keys = Vec::new();
+        // algo/ab/cd/rest, which also skips over in-progress writes in tmp/
+        for path in files_at_depth(&self.cache_path, 4)? {
+            keys.push(path.strip_prefix(&self.cache_path)?.to_path_buf());
+        }
+        Ok(keys)
+    }
+
+    fn append_index(&self, bucket: &str, line: &str) -> anyhow::Result<()> {
+        let bucket_path = self.bucket_path(bucket);
+        let parent = bucket_path
+            .parent()
+            .context("couldnt get parent in 'key' path")?;
+        DirBuilder::new().recursive(true).create(parent)?;
+
+        // appends arent atomic like a rename, but a torn line fails its checksum and is skipped
+        let _lock = DirLock::exclusive(&self.index_path)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&bucket_path)?;
+        file.write_all(line.as_bytes())?;
+        if self.fsync != Fsync::Never {
+            file.sync_all()?;
+        }
+        if self.fsync == Fsync::FileAndDir {
+            sync_dir(parent)?;
+        }
+        Ok(())
+    }
+
+    fn read_index(&self, bucket: &str) -> anyhow::Result<Option<String>> {
+        let _lock = DirLock::shared(&self.index_path)?;
+        match std::fs::read_to_string(self.bucket_path(bucket)) {
+            Ok(raw) => Ok(Some(raw)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn buckets(&self) -> anyhow::Result<Vec<String>> {
+        let mut buckets = Vec::new();
+        for path in files_at_depth(&self.index_path, 3)? {
+            let relative = path.strip_prefix(&self.index_path)?;
+            let bucket: String = relative.iter().map(|part| part.to_string_lossy()).collect();
+            buckets.push(bucket);
+        }
+        Ok(buckets)
+    }
+
+    /// Wipes every bucket and all content, leaving the lock file so concurrent lockers dont split
+    fn clear(&self) -> anyhow::Result<()> {
+        let _lock = DirLock::exclusive(&self.index_path)?;
+        for entry in std::fs::read_dir(&self.index_path)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                std::fs::remove_dir_all(entry.path())?;
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        match std::fs::remove_dir_all(&self.cache_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Files exactly `depth` levels below `dir`, matching the ab/cd/rest bucket layout
+fn files_at_depth(dir: &Path, depth: usize) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in read_dir {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if depth == 1 && file_type.is_file() {
+            files.push(entry.path());
+        } else if depth > 1 && file_type.is_dir() {
+            files.extend(files_at_depth(&entry.path(), depth - 1)?);
+        }
+    }
+    Ok(files)
+}
+
+struct FsStagedContent<'a> {
+    storage: &'a FsStorage,
+    file: Option<File>,
+    tmp_path: PathBuf,
+}
+
+impl StagedContent for FsStagedContent<'_> {
+    fn commit(mut self: Box<Self>, key: &Path) -> anyhow::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+            if self.storage.fsync != Fsync::Never {
+                file.sync_all()?;
+            }
+        }
+
+        let path = self.storage.content_path(key);
+        if path.exists() {
+            std::fs::remove_file(&self.tmp_path)?;
+        } else {
+            persist(&self.tmp_path, &path, self.storage.fsync)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for FsStagedContent<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("content already committed"))?
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FsStagedContent<'_> {
+    fn drop(&mut self) {
+        // still holding the file means commit never ran
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn storage(dir: &TempDir) -> FsStorage {
+        FsStorage::new(
+            dir.path().join("index"),
+            dir.path().join("cache"),
+            Fsync::Never,
+        )
+    }
+
+    #[test]
+    fn save_data_in_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let content = b"hello";
+        let key = Path::new("sha256/LP/JN/ul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+        storage.write_content(key, content).unwrap();
+
+        let path = storage.content_path(key);
+        assert!(path.starts_with(dir.path().join("cache/sha256")));
+
+        let cache_content = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(cache_content.as_bytes(), content);
+    }
+
+    #[test]
+    fn dropped_stage_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let mut staged = storage.stage_content().unwrap();
+        staged.write_all(b"never committed").unwrap();
+        drop(staged);
+
+        let leftover_tmp = std::fs::read_dir(storage.tmp_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .any(|name| name.starts_with(&format!(".stream.tmp.{}.", std::process::id())));
+        assert!(!leftover_tmp);
+    }
+
+    #[test]
+    fn buckets_round_trip_through_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let bucket = "0123456789abcdef";
+        storage.append_index(bucket, "\nline").unwrap();
+
+        assert_eq!(storage.buckets().unwrap(), vec![bucket.to_string()]);
+        assert!(storage.content_keys().unwrap().is_empty());
+
+        let key = Path::new("sha256/ab/cd/efgh=");
+        storage.write_content(key, b"content").unwrap();
+        let mut staged = storage.stage_content().unwrap();
+        staged.write_all(b"in progress").unwrap();
+        assert_eq!(storage.content_keys().unwrap(), vec![key.to_path_buf()]);
+        drop(staged);
+        assert_eq!(storage.read_index(bucket).unwrap().unwrap(), "\nline");
+        assert!(storage.read_index("fedcba9876543210").unwrap().is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+use super::{StagedContent, Storage};
+
+/// Keeps everything in process memory, gone when dropped. Handy for tests and for
+/// embedders that only want deduplication within a single run.
+#[derive(Default)]
+pub struct MemoryStorage {
+    content: Mutex<HashMap<PathBuf, Arc<[u8]>>>,
+    index: Mutex<BTreeMap<String, String>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn content_path(&self, key: &Path) -> PathBuf {
+        key.to_path_buf()
+    }
+
+    fn has_content(&self, key: &Path) -> anyhow::Result<bool> {
+        Ok(lock(&self.content)?.contains_key(key))
+    }
+
+    fn write_content(&self, key: &Path, content: &[u8]) -> anyhow::Result<()> {
+        lock(&self.content)?
+            .entry(key.to_path_buf())
+            .or_insert_with(|| Arc::from(content));
+        Ok(())
+    }
+
+    fn stage_content(&self) -> anyhow::Result<Box<dyn StagedContent + '_>> {
+        Ok(Box::new(MemoryStagedContent {
+            storage: self,
+            buffer: Vec::new(),
+        }))
+    }
+
+    fn read_content(&self, key: &Path) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        let content = lock(&self.content)?
+            .get(key)
+            .cloned()
+            .context("content not found in memory storage")?;
+        Ok(Box::new(Cursor::new(content)))
+    }
+
+    fn remove_content(&self, key: &Path) -> anyhow::Result<bool> {
+        Ok(lock(&self.content)?.remove(key).is_some())
+    }
+
+    fn content_keys(&self) -> anyhow::Result<Vec<PathBuf>> {
+        Ok(lock(&self.content)?.keys().cloned().collect())
+    }
+
+    fn append_index(&self, bucket: &str, line: &str) -> anyhow::Result<()> {
+        lock(&self.index)?
+            .entry(bucket.to_string())
+            .or_default()
+            .push_str(line);
+        Ok(())
+    }
+
+    fn read_index(&self, bucket: &str) -> anyhow::Result<Option<String>> {
+        Ok(lock(&self.index)?.get(bucket).cloned())
+    }
+
+    fn buckets(&self) -> anyhow::Result<Vec<String>> {
+        Ok(lock(&self.index)?.keys().cloned().collect())
+    }
+
+    fn clear(&self) -> anyhow::Result<()> {
+        lock(&self.content)?.clear();
+        lock(&self.index)?.clear();
+        Ok(())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> anyhow::Result<std::sync::MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| anyhow::anyhow!("memory storage lock poisoned"))
+}
+
+struct MemoryStagedContent<'a> {
+    storage: &'a MemoryStorage,
+    buffer: Vec<u8>,
+}
+
+impl StagedContent for MemoryStagedContent<'_> {
+    fn commit(self: Box<Self>, key: &Path) -> anyhow::Result<()> {
+        self.storage.write_content(key, &self.buffer)
+    }
+}
+
+impl Write for MemoryStagedContent<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
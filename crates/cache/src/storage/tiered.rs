@@ -0,0 +1,154 @@
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{StagedContent, Storage};
+
+/// Serves content from a fast `front` store (usually `MemoryStorage`) in front of a
+/// durable `back` one (usually `FsStorage`). Content is written through to both and
+/// copied forward on the first read that misses the front. The index only lives in
+/// `back`, so other processes sharing it never see a stale view.
+///
+/// The front has no size cap and nothing is ever evicted from it: it grows to hold
+/// everything written or read through here, so only tier caches that fit in memory.
+pub struct TieredStorage<F: Storage, B: Storage> {
+    front: F,
+    back: B,
+}
+
+impl<F: Storage, B: Storage> TieredStorage<F, B> {
+    pub fn new(front: F, back: B) -> Self {
+        Self { front, back }
+    }
+}
+
+impl<F: Storage, B: Storage> Storage for TieredStorage<F, B> {
+    fn content_path(&self, key: &Path) -> PathBuf {
+        self.back.content_path(key)
+    }
+
+    fn has_content(&self, key: &Path) -> anyhow::Result<bool> {
+        Ok(self.front.has_content(key)? || self.back.has_content(key)?)
+    }
+
+    fn write_content(&self, key: &Path, content: &[u8]) -> anyhow::Result<()> {
+        self.back.write_content(key, content)?;
+        self.front.write_content(key, content)
+    }
+
+    /// Streams straight to `back`; the front picks it up on first read
+    fn stage_content(&self) -> anyhow::Result<Box<dyn StagedContent + '_>> {
+        self.back.stage_content()
+    }
+
+    fn read_content(&self, key: &Path) -> anyhow::Result<Box<dyn Read + Send + '_>> {
+        if self.front.has_content(key)? {
+            return self.front.read_content(key);
+        }
+        Ok(Box::new(Promote {
+            back: self.back.read_content(key)?,
+            staged: Some(self.front.stage_content()?),
+            key: key.to_path_buf(),
+        }))
+    }
+
+    fn remove_content(&self, key: &Path) -> anyhow::Result<bool> {
+        let front = self.front.remove_content(key)?;
+        Ok(self.back.remove_content(key)? || front)
+    }
+
+    /// Everything in `front` was written through or copied from `back`
+    fn content_keys(&self) -> anyhow::Result<Vec<PathBuf>> {
+        self.back.content_keys()
+    }
+
+    fn append_index(&self, bucket: &str, line: &str) -> anyhow::Result<()> {
+        self.back.append_index(bucket, line)
+    }
+
+    fn read_index(&self, bucket: &str) -> anyhow::Result<Option<String>> {
+        self.back.read_index(bucket)
+    }
+
+    fn buckets(&self) -> anyhow::Result<Vec<String>> {
+        self.back.buckets()
+    }
+
+    fn clear(&self) -> anyhow::Result<()> {
+        self.front.clear()?;
+        self.back.clear()
+    }
+}
+
+/// Hands out `back`'s bytes as they are read while copying them into `front`, which only gets
+/// the content once all of it has been read. Unverified here, the reader checks it, and a
+/// corrupt copy in front is no worse than in back
+struct Promote<'a> {
+    back: Box<dyn Read + Send + 'a>,
+    // dropped, and so discarded, if the read fails or stops early
+    staged: Option<Box<dyn StagedContent + 'a>>,
+    key: PathBuf,
+}
+
+impl Read for Promote<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.back.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            if let Some(staged) = self.staged.take() {
+                // promoting is only an optimisation, the read itself worked
+                let _ = staged.commit(&self.key);
+            }
+        } else if let Some(staged) = &mut self.staged
+            && staged.write_all(&buf[..read]).is_err()
+        {
+            self.staged = None;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn reads_are_promoted_to_front() {
+        let back = MemoryStorage::new();
+        let key = Path::new("sha256/ti/er/ed");
+        back.write_content(key, b"tiered").unwrap();
+
+        let tiered = TieredStorage::new(MemoryStorage::new(), back);
+        assert!(!tiered.front.has_content(key).unwrap());
+
+        let mut content = String::new();
+        tiered
+            .read_content(key)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "tiered");
+        assert!(tiered.front.has_content(key).unwrap());
+
+        assert!(tiered.remove_content(key).unwrap());
+        assert!(!tiered.has_content(key).unwrap());
+    }
+
+    #[test]
+    fn partial_reads_are_not_promoted() {
+        let back = MemoryStorage::new();
+        let key = Path::new("sha256/ti/er/ed");
+        back.write_content(key, b"tiered").unwrap();
+        let tiered = TieredStorage::new(MemoryStorage::new(), back);
+
+        let mut start = [0; 3];
+        tiered
+            .read_content(key)
+            .unwrap()
+            .read_exact(&mut start)
+            .unwrap();
+        assert_eq!(&start, b"tie");
+        assert!(!tiered.front.has_content(key).unwrap());
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{Read, Write};
+
+use serde::Serialize;
+use ssri::{Integrity, IntegrityChecker, IntegrityOpts};
+
+use crate::{
+    Cache, Index,
+    compression::{Compression, Encoder},
+    content::content_key,
+    index::add_index,
+    storage::StagedContent,
+};
+
+/// Streams a value into the cache without holding it in memory. Nothing is visible
+/// to readers until `commit`; dropping the writer first throws the partial data away.
+pub struct Writer<'a, K: Serialize + Index> {
+    cache: &'a Cache,
+    key: &'a mut K,
+    staged: Encoder<Box<dyn StagedContent + 'a>>,
+    compression: Compression,
+    hasher: IntegrityOpts,
+    size: usize,
+    metadata: serde_json::Value,
+}
+
+impl<'a, K: Serialize + Index> Writer<'a, K> {
+    pub(crate) fn new(cache: &'a Cache, key: &'a mut K) -> anyhow::Result<Self> {
+        let compression = cache.options.compression;
+        Ok(Self {
+            cache,
+            key,
+            staged: Encoder::new(compression, cache.storage.stage_content()?)?,
+            compression,
+            hasher: cache.options.hasher(),
+            size: 0,
+            metadata: serde_json::Value::Null,
+        })
+    }
+
+    /// Metadata to store in the index entry on commit
+    pub fn set_metadata(&mut self, metadata: impl Serialize) -> anyhow::Result<()> {
+        self.metadata = serde_json::to_value(metadata)?;
+        Ok(())
+    }
+
+    /// Moves the written content into the store and indexes it under the key
+    pub fn commit(self) -> anyhow::Result<Integrity> {
+        let storage = &*self.cache.storage;
+        let integrity = self.hasher.result();
+        let content_key = content_key(&integrity, self.compression)?;
+        self.staged.finish()?.commit(&content_key)?;
+
+        add_index(
+            storage,
+            self.key,
+            storage.content_path(&content_key),
+            &integrity,
+            self.size,
+            self.compression,
+            self.metadata,
+        )?;
+        Ok(integrity)
+    }
+}
+
+impl<K: Serialize + Index> Write for Writer<'_, K> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // hash what went in, not what the encoder wrote out
+        let n = self.staged.write(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.size += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.staged.flush()
+    }
+}
+
+/// Streams a value out of the cache, hashing as it goes. The integrity check happens
+/// when the end is reached, so corrupt content surfaces as an `InvalidData` error from
+/// the final `read`. Stopping early skips the check.
+pub struct Reader<'a> {
+    inner: Box<dyn Read + Send + 'a>,
+    checker: Option<IntegrityChecker>,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(inner: Box<dyn Read + Send + 'a>, integrity: Integrity) -> Self {
+        Self {
+            inner,
+            checker: Some(IntegrityChecker::new(integrity)),
+        }
+    }
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            if let Some(checker) = self.checker.as_mut() {
+                checker.input(&buf[..n]);
+            }
+        } else if !buf.is_empty()
+            && let Some(checker) = self.checker.take()
+        {
+            checker
+                .result()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        storage::{MemoryStorage, Storage},
+        testing::Key,
+    };
+
+    #[test]
+    fn stream_in_and_out() {
+        let cache = Cache::in_memory();
+        let mut key = Key::new("stream_in_and_out");
+        let chunk = b"a chunk of a large download ";
+
+        let mut writer = cache.writer(&mut key).unwrap();
+        for _ in 0..1000 {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.set_metadata("streamed").unwrap();
+        let integrity = writer.commit().unwrap();
+
+        let expected = chunk.repeat(1000);
+        assert_eq!(integrity, Integrity::from(&expected));
+        assert!(key.value.is_some());
+
+        let mut read_back = Vec::new();
+        cache
+            .reader(&Key::new("stream_in_and_out"))
+            .unwrap()
+            .read_to_end(&mut read_back)
+            .unwrap();
+        assert_eq!(read_back, expected);
+    }
+
+    #[test]
+    fn reader_rejects_corrupt_content() {
+        let storage = MemoryStorage::new();
+        let integrity = Integrity::from("original");
+        let key = content_key(&integrity, Compression::None).unwrap();
+        storage.write_content(&key, b"not what was hashed").unwrap();
+
+        let mut reader = Reader::new(storage.read_content(&key).unwrap(), integrity);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dropped_writer_is_not_indexed() {
+        let cache = Cache::in_memory();
+        let mut key = Key::new("dropped_writer");
+
+        let mut writer = cache.writer(&mut key).unwrap();
+        writer.write_all(b"never committed").unwrap();
+        drop(writer);
+
+        assert!(!cache.contains(&key).unwrap());
+        assert!(
+            cache
+                .get_by_integrity(&Integrity::from("never committed"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn stream_compressed() {
+        let cache = Cache::with_storage(
+            MemoryStorage::new(),
+            crate::CacheOptions {
+                compression: Compression::Gzip,
+                ..Default::default()
+            },
+        );
+        let mut key = Key::new("stream_compressed");
+        let chunk = b"<tr><td>row</td></tr>";
+
+        let mut writer = cache.writer(&mut key).unwrap();
+        for _ in 0..500 {
+            writer.write_all(chunk).unwrap();
+        }
+        let integrity = writer.commit().unwrap();
+
+        let expected = chunk.repeat(500);
+        assert_eq!(integrity, Integrity::from(&expected));
+        assert!(key.value.unwrap().extension().is_some());
+        assert_eq!(cache.get_by_integrity(&integrity).unwrap(), expected);
+    }
+}
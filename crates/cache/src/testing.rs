@@ -0,0 +1,22 @@
+//! The key the tests cache things under
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, cache_derive::Index)]
+pub(crate) struct Key {
+    pub(crate) data: String,
+    #[index(value_path)]
+    pub(crate) value: Option<PathBuf>,
+}
+
+impl Key {
+    /// A key that hasnt been saved under yet
+    pub(crate) fn new(data: &str) -> Self {
+        Self {
+            data: data.into(),
+            value: None,
+        }
+    }
+}
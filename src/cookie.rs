@@ -0,0 +1,574 @@
+use std::{
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use publicsuffix::{List, Psl};
+
+use crate::url::{Scheme, URL};
+
+mod netscape;
+
+/// Cookies may not outlive this, whatever Expires or Max-Age say
+const MAX_AGE_CAP: Duration = Duration::from_secs(400 * 24 * 60 * 60);
+/// Name plus value may be at most this many bytes, as may each attribute value
+const MAX_SIZE: usize = 4096;
+/// A snapshot of https://publicsuffix.org/list/public_suffix_list.dat, refresh it now and then
+const PUBLIC_SUFFIX_LIST: &str = include_str!("cookie/public_suffix_list.dat");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+    /// No SameSite attribute, enforced like Lax
+    #[default]
+    Default,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Lowercased, without a leading dot
+    pub domain: String,
+    /// Only sent to exactly `domain`, not its subdomains
+    pub host_only: bool,
+    pub path: String,
+    /// None for session cookies
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSite,
+    pub creation: SystemTime,
+}
+
+impl Cookie {
+    /// Parses a `Set-Cookie` value received from `url`, leaving domain checks to the jar.
+    /// Returns None for headers that must be ignored entirely
+    pub fn parse(header: &str, url: &URL) -> Option<Self> {
+        let now = SystemTime::now();
+        let (pair, attributes) = match header.split_once(';') {
+            Some((pair, attributes)) => (pair, attributes),
+            None => (header, ""),
+        };
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => ("", pair.trim()),
+        };
+        if name.is_empty() && value.is_empty() || name.len() + value.len() > MAX_SIZE {
+            return None;
+        }
+        if name
+            .bytes()
+            .chain(value.bytes())
+            .any(|b| b.is_ascii_control())
+        {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: String::new(),
+            host_only: true,
+            path: default_path(url),
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: SameSite::Default,
+            creation: now,
+        };
+        let mut max_age = None;
+        let mut expires = None;
+
+        for attribute in attributes.split(';') {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (attribute.trim(), ""),
+            };
+            if value.len() > MAX_SIZE {
+                continue;
+            }
+            match key.to_ascii_lowercase().as_str() {
+                "expires" => {
+                    if let Ok(time) = httpdate::parse_http_date(value) {
+                        expires = Some(time);
+                    }
+                }
+                "max-age" => {
+                    let valid = value.strip_prefix('-').unwrap_or(value);
+                    if valid.is_empty() || !valid.bytes().all(|b| b.is_ascii_digit()) {
+                        continue;
+                    }
+                    // anything unparsable at this point is huge, so gets capped anyway
+                    let seconds = value.parse::<i64>().unwrap_or(i64::MAX);
+                    max_age = Some(if seconds <= 0 {
+                        SystemTime::UNIX_EPOCH
+                    } else {
+                        now + Duration::from_secs(seconds as u64).min(MAX_AGE_CAP)
+                    });
+                }
+                "domain" => {
+                    let domain = value.strip_prefix('.').unwrap_or(value);
+                    if !domain.is_empty() {
+                        cookie.domain = domain.to_ascii_lowercase();
+                        cookie.host_only = false;
+                    }
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => {
+                    cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                        "strict" => SameSite::Strict,
+                        "lax" => SameSite::Lax,
+                        "none" => SameSite::None,
+                        _ => SameSite::Default,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Max-Age wins over Expires
+        cookie.expires = max_age.or(expires.map(|time| time.min(now + MAX_AGE_CAP)));
+        Some(cookie)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|time| time <= SystemTime::now())
+    }
+
+    /// Whether this cookie belongs on a request to `url`, ignoring SameSite
+    pub fn matches(&self, url: &URL) -> bool {
+        let Some(host) = url.hostname().map(str::to_ascii_lowercase) else {
+            return false;
+        };
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+        domain_ok
+            && path_match(request_path(url), &self.path)
+            && (!self.secure || is_secure(url))
+            && !self.is_expired()
+    }
+}
+
+/// Holds every cookie the engine has been given and picks the ones to send with each request
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+    public_suffixes: List,
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CookieJar {
+    /// A jar using the bundled public suffix list, so `co.uk` or `github.io` cant get cookies
+    /// for every site under them
+    pub fn new() -> Self {
+        static LIST: OnceLock<List> = OnceLock::new();
+        let list = LIST.get_or_init(|| {
+            PUBLIC_SUFFIX_LIST
+                .parse()
+                .expect("the bundled public suffix list parses")
+        });
+        Self::with_public_suffixes(list.clone())
+    }
+
+    /// A jar using a list parsed from https://publicsuffix.org/list/public_suffix_list.dat
+    pub fn with_public_suffixes(public_suffixes: List) -> Self {
+        Self {
+            cookies: Mutex::new(Vec::new()),
+            public_suffixes,
+        }
+    }
+
+    /// Stores every `Set-Cookie` value of a response from `url`, skipping rejected ones
+    pub fn store_response_cookies<'a>(
+        &self,
+        url: &URL,
+        headers: impl IntoIterator<Item = &'a str>,
+    ) {
+        for header in headers {
+            if let Some(cookie) = Cookie::parse(header, url) {
+                self.insert(cookie, url);
+            }
+        }
+    }
+
+    /// Runs the storage model checks for a cookie set by `url` and stores it, returning whether
+    /// it was accepted
+    pub fn insert(&self, mut cookie: Cookie, url: &URL) -> bool {
+        let Some(host) = url.hostname().map(str::to_ascii_lowercase) else {
+            return false;
+        };
+
+        if cookie.host_only {
+            cookie.domain = host.clone();
+        } else if self.is_public_suffix(&cookie.domain) {
+            // a public suffix may only set a cookie for itself
+            if cookie.domain != host {
+                return false;
+            }
+            cookie.host_only = true;
+        } else if !domain_match(&host, &cookie.domain) {
+            return false;
+        }
+
+        if cookie.secure && !is_secure(url) {
+            return false;
+        }
+        if cookie.same_site == SameSite::None && !cookie.secure {
+            return false;
+        }
+        if starts_with_ignore_case(&cookie.name, "__Secure-") && !cookie.secure {
+            return false;
+        }
+        if starts_with_ignore_case(&cookie.name, "__Host-")
+            && !(cookie.secure && cookie.host_only && cookie.path == "/")
+        {
+            return false;
+        }
+
+        let mut cookies = self.cookies.lock().unwrap();
+        // an insecure origin cant shadow a secure cookie of the same name
+        if !is_secure(url)
+            && cookies.iter().any(|old| {
+                old.secure
+                    && old.name == cookie.name
+                    && (domain_match(&cookie.domain, &old.domain)
+                        || domain_match(&old.domain, &cookie.domain))
+                    && path_match(&cookie.path, &old.path)
+            })
+        {
+            return false;
+        }
+
+        if let Some(i) = cookies.iter().position(|old| {
+            old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path
+        }) {
+            cookie.creation = cookies[i].creation;
+            cookies.remove(i);
+        }
+        if !cookie.is_expired() {
+            cookies.push(cookie);
+        }
+        true
+    }
+
+    /// The `Cookie` header value for a request to `url`, if any cookies apply.
+    /// `same_site` is false when the request (or a redirect before it) crossed sites,
+    /// which holds back `SameSite=Strict` cookies
+    pub fn cookie_header(&self, url: &URL, same_site: bool) -> Option<String> {
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired());
+
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|cookie| cookie.matches(url))
+            .filter(|cookie| same_site || cookie.same_site != SameSite::Strict)
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        // longer paths first, then oldest first
+        matching.sort_by(|a, b| {
+            b.path
+                .len()
+                .cmp(&a.path.len())
+                .then(a.creation.cmp(&b.creation))
+        });
+
+        let pairs: Vec<String> = matching
+            .iter()
+            .map(|cookie| {
+                if cookie.name.is_empty() {
+                    cookie.value.clone()
+                } else {
+                    format!("{}={}", cookie.name, cookie.value)
+                }
+            })
+            .collect();
+        Some(pairs.join("; "))
+    }
+
+    /// Whether two urls are the same site: same scheme and same registrable domain
+    pub fn same_site(&self, a: &URL, b: &URL) -> bool {
+        a.scheme() == b.scheme() && self.site(a) == self.site(b)
+    }
+
+    fn site(&self, url: &URL) -> Option<String> {
+        let host = url.hostname()?.to_ascii_lowercase();
+        if host.parse::<IpAddr>().is_ok() {
+            return Some(host);
+        }
+        match self.public_suffixes.domain(host.as_bytes()) {
+            Some(domain) => Some(String::from_utf8_lossy(domain.as_bytes()).into_owned()),
+            None => Some(host),
+        }
+    }
+
+    fn is_public_suffix(&self, domain: &str) -> bool {
+        if domain.parse::<IpAddr>().is_ok() {
+            return false;
+        }
+        self.public_suffixes
+            .suffix(domain.as_bytes())
+            .is_some_and(|suffix| suffix.as_bytes() == domain.as_bytes())
+    }
+
+    /// A snapshot of every unexpired cookie
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired());
+        cookies.clone()
+    }
+
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+}
+
+fn is_secure(url: &URL) -> bool {
+    url.scheme() == Scheme::Https
+        || url
+            .hostname()
+            .is_some_and(|host| host == "localhost" || host == "127.0.0.1" || host == "::1")
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<IpAddr>().is_err())
+}
+
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/')
+                || request_path.as_bytes().get(cookie_path.len()) == Some(&b'/')))
+}
+
+/// The url's path without its query or fragment
+fn request_path(url: &URL) -> &str {
+    let path = url.path().unwrap_or("/");
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    if path.is_empty() { "/" } else { path }
+}
+
+/// The directory of the request path, used when a cookie doesnt set Path
+fn default_path(url: &URL) -> String {
+    let path = request_path(url);
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn url(s: &str) -> URL {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_attributes() {
+        let cookie = Cookie::parse(
+            "id=a3fWa; Max-Age=60; Domain=.Example.com; Path=/docs; Secure; HttpOnly; SameSite=Strict",
+            &url("https://www.example.com/"),
+        )
+        .unwrap();
+        assert_eq!(cookie.name, "id");
+        assert_eq!(cookie.value, "a3fWa");
+        assert_eq!(cookie.domain, "example.com");
+        assert!(!cookie.host_only);
+        assert_eq!(cookie.path, "/docs");
+        assert!(cookie.secure && cookie.http_only);
+        assert_eq!(cookie.same_site, SameSite::Strict);
+        assert!(cookie.expires.unwrap() > SystemTime::now());
+
+        let cookie = Cookie::parse("lang", &url("http://example.com/a/b/c")).unwrap();
+        assert_eq!(cookie.name, "");
+        assert_eq!(cookie.value, "lang");
+        assert_eq!(cookie.path, "/a/b");
+        assert_eq!(cookie.expires, None);
+
+        assert!(Cookie::parse("=", &url("http://example.com/")).is_none());
+    }
+
+    #[test]
+    fn max_age_wins_over_expires() {
+        let cookie = Cookie::parse(
+            "a=b; Max-Age=0; Expires=Wed, 21 Oct 2099 07:28:00 GMT",
+            &url("http://example.com/"),
+        )
+        .unwrap();
+        assert!(cookie.is_expired());
+
+        let cookie = Cookie::parse(
+            "a=b; Expires=Wed, 21 Oct 2099 07:28:00 GMT",
+            &url("http://example.com/"),
+        )
+        .unwrap();
+        assert!(cookie.expires.unwrap() <= SystemTime::now() + MAX_AGE_CAP);
+    }
+
+    #[test]
+    fn domain_and_path_matching() {
+        let jar = CookieJar::new();
+        let origin = url("http://www.example.com/");
+        jar.store_response_cookies(
+            &origin,
+            [
+                "host=1",
+                "domain=2; Domain=example.com",
+                "docs=3; Path=/docs",
+            ],
+        );
+
+        assert_eq!(
+            jar.cookie_header(&url("http://www.example.com/"), true)
+                .unwrap(),
+            "host=1; domain=2"
+        );
+        assert_eq!(
+            jar.cookie_header(&url("http://www.example.com/docs/x?y=/z"), true)
+                .unwrap(),
+            "docs=3; host=1; domain=2"
+        );
+        assert_eq!(
+            jar.cookie_header(&url("http://api.example.com/"), true)
+                .unwrap(),
+            "domain=2"
+        );
+        assert_eq!(
+            jar.cookie_header(&url("http://www.example.com/docsets"), true)
+                .unwrap(),
+            "host=1; domain=2"
+        );
+        assert!(
+            jar.cookie_header(&url("http://example.org/"), true)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rejects_foreign_and_public_suffix_domains() {
+        let list = "// ===BEGIN ICANN DOMAINS===\nuk\nco.uk\ncom";
+        let jar = CookieJar::with_public_suffixes(list.parse().unwrap());
+        let origin = url("http://shop.example.co.uk/");
+        jar.store_response_cookies(
+            &origin,
+            [
+                "a=1; Domain=co.uk",
+                "b=2; Domain=uk",
+                "c=3; Domain=other.co.uk",
+                "d=4; Domain=example.co.uk",
+            ],
+        );
+        let names: Vec<String> = jar.cookies().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["d"]);
+
+        // the default jar knows the real list
+        let jar = CookieJar::new();
+        jar.store_response_cookies(&url("http://a.co.uk/"), ["a=1; Domain=co.uk"]);
+        jar.store_response_cookies(&url("https://me.github.io/"), ["b=2; Domain=github.io"]);
+        jar.store_response_cookies(&url("http://a.co.uk/"), ["c=3; Domain=a.co.uk"]);
+        let names: Vec<String> = jar.cookies().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["c"]);
+
+        // a public suffix can still set a host-only cookie for itself
+        let jar = CookieJar::new();
+        jar.store_response_cookies(&url("http://localhost/"), ["a=1; Domain=localhost"]);
+        let cookie = &jar.cookies()[0];
+        assert!(cookie.host_only);
+    }
+
+    #[test]
+    fn loopback_counts_as_secure() {
+        for base in ["http://localhost/", "http://127.0.0.1/", "http://[::1]/"] {
+            let jar = CookieJar::new();
+            jar.store_response_cookies(&url(base), ["s=1; Secure"]);
+            assert_eq!(jar.cookie_header(&url(base), true).as_deref(), Some("s=1"));
+        }
+    }
+
+    #[test]
+    fn secure_and_same_site_rules() {
+        let jar = CookieJar::new();
+        jar.store_response_cookies(
+            &url("http://example.com/"),
+            [
+                "s=1; Secure",
+                "n=2; SameSite=None",
+                "__Host-h=3; Secure; Path=/",
+            ],
+        );
+        assert!(jar.cookies().is_empty());
+
+        jar.store_response_cookies(
+            &url("https://example.com/"),
+            [
+                "s=1; Secure",
+                "strict=2; SameSite=Strict",
+                "__Host-h=3; Secure; Path=/",
+                "__Host-bad=4; Secure; Path=/; Domain=example.com",
+            ],
+        );
+        assert_eq!(
+            jar.cookie_header(&url("https://example.com/"), true)
+                .unwrap(),
+            "s=1; strict=2; __Host-h=3"
+        );
+        assert_eq!(
+            jar.cookie_header(&url("https://example.com/"), false)
+                .unwrap(),
+            "s=1; __Host-h=3"
+        );
+        assert_eq!(
+            jar.cookie_header(&url("http://example.com/"), true)
+                .unwrap(),
+            "strict=2"
+        );
+
+        // plain http cant overwrite the secure cookie
+        jar.store_response_cookies(&url("http://example.com/"), ["s=evil"]);
+        assert!(jar.cookies().iter().all(|c| c.value != "evil"));
+    }
+
+    #[test]
+    fn replacing_and_expiring() {
+        let jar = CookieJar::new();
+        let origin = url("http://example.com/");
+        jar.store_response_cookies(&origin, ["a=1", "b=2"]);
+        jar.store_response_cookies(&origin, ["a=3", "b=; Max-Age=0"]);
+        assert_eq!(jar.cookie_header(&origin, true).unwrap(), "a=3");
+    }
+
+    #[test]
+    fn same_site() {
+        let jar = CookieJar::new();
+        assert!(jar.same_site(
+            &url("https://a.example.com/"),
+            &url("https://b.example.com/x")
+        ));
+        assert!(!jar.same_site(&url("https://example.com/"), &url("http://example.com/")));
+        assert!(!jar.same_site(&url("https://example.com/"), &url("https://example.org/")));
+    }
+}
@@ -0,0 +1,238 @@
+//! The Netscape `cookies.txt` format read and written by curl and wget: one cookie per line,
+//! with tab separated domain, include subdomains, path, secure, expiry, name and value
+
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+use super::{Cookie, CookieJar, SameSite};
+
+const HEADER: &str = "# Netscape HTTP Cookie File";
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// Tells apart the temp files of saves running at once in this process
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl CookieJar {
+    /// Adds every unexpired cookie in a cookies.txt file, replacing cookies with the same name,
+    /// domain and path. Returns how many were loaded
+    pub fn load(&self, path: impl AsRef<Path>) -> anyhow::Result<usize> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("couldnt open {}", path.display()))?;
+        self.read_netscape(file)
+    }
+
+    /// Writes every unexpired cookie to a cookies.txt file, replacing the file. The cookies go
+    /// to a temp file beside it that is then renamed over it, so a crash or error midway leaves
+    /// the old file whole
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("no file name in {}", path.display()))?;
+        let tmp = path.with_file_name(format!(
+            ".{}.tmp.{}.{}",
+            file_name.to_string_lossy(),
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let saved = self.write_file(&tmp).and_then(|()| {
+            fs::rename(&tmp, path).with_context(|| format!("couldnt replace {}", path.display()))
+        });
+        if saved.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        saved
+    }
+
+    fn write_file(&self, path: &Path) -> anyhow::Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("couldnt create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_netscape(&mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+
+    /// Lines that arent cookies, like the odd comments and stray text real files have, are
+    /// skipped rather than failing the whole load
+    pub fn read_netscape(&self, reader: impl Read) -> anyhow::Result<usize> {
+        let mut loaded = 0;
+        let mut cookies = self.cookies.lock().unwrap();
+        for line in BufReader::new(reader).lines() {
+            let Some(cookie) = parse_line(&line?) else {
+                continue;
+            };
+            cookies.retain(|old| {
+                !(old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path)
+            });
+            if !cookie.is_expired() {
+                cookies.push(cookie);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    pub fn write_netscape(&self, mut writer: impl Write) -> anyhow::Result<()> {
+        writeln!(writer, "{}", HEADER)?;
+        for cookie in self.cookies() {
+            let domain = if cookie.host_only {
+                cookie.domain.clone()
+            } else {
+                format!(".{}", cookie.domain)
+            };
+            let expires = cookie
+                .expires
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs());
+            writeln!(
+                writer,
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                if cookie.http_only {
+                    HTTP_ONLY_PREFIX
+                } else {
+                    ""
+                },
+                domain,
+                flag(!cookie.host_only),
+                cookie.path,
+                flag(cookie.secure),
+                expires,
+                cookie.name,
+                cookie.value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn flag(value: bool) -> &'static str {
+    if value { "TRUE" } else { "FALSE" }
+}
+
+/// None for blank and comment lines, and for anything else that isnt a cookie
+fn parse_line(line: &str) -> Option<Cookie> {
+    let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    if line.trim().is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+    // the value may be empty, in which case some writers drop the last tab
+    let [domain, subdomains, path, secure, expires, name, value @ ..] = fields.as_slice() else {
+        return None;
+    };
+    let value = value.first().copied().unwrap_or_default();
+
+    let host_only = !subdomains.eq_ignore_ascii_case("TRUE");
+    let domain = domain
+        .strip_prefix('.')
+        .unwrap_or(domain)
+        .to_ascii_lowercase();
+    if domain.is_empty() || domain.contains(char::is_whitespace) {
+        return None;
+    }
+    let expires: u64 = expires.parse().ok()?;
+
+    Some(Cookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain,
+        host_only,
+        path: path.to_string(),
+        // curl writes session cookies with an expiry of 0
+        expires: (expires != 0).then(|| UNIX_EPOCH + Duration::from_secs(expires)),
+        secure: secure.eq_ignore_ascii_case("TRUE"),
+        http_only,
+        same_site: SameSite::Default,
+        creation: SystemTime::now(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let jar = CookieJar::new();
+        let url = "https://www.example.com/account/login".parse().unwrap();
+        jar.store_response_cookies(
+            &url,
+            [
+                "session=abc",
+                "id=42; Domain=example.com; Path=/; Max-Age=3600; Secure; HttpOnly",
+            ],
+        );
+
+        let mut file = Vec::new();
+        jar.write_netscape(&mut file).unwrap();
+        let text = String::from_utf8(file.clone()).unwrap();
+        assert!(text.starts_with(HEADER));
+        assert!(text.contains("www.example.com\tFALSE\t/account\tFALSE\t0\tsession\tabc"));
+        assert!(text.contains("#HttpOnly_.example.com\tTRUE\t/\tTRUE\t"));
+
+        let loaded = CookieJar::new();
+        assert_eq!(loaded.read_netscape(file.as_slice()).unwrap(), 2);
+        let mut expected = jar.cookies();
+        let mut actual = loaded.cookies();
+        for cookie in expected.iter_mut().chain(actual.iter_mut()) {
+            cookie.creation = UNIX_EPOCH;
+            // the file only has whole seconds
+            cookie.expires = cookie.expires.map(|time| {
+                UNIX_EPOCH + Duration::from_secs(time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+            });
+        }
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn prunes_expired_and_skips_comments() {
+        let file = "# Netscape HTTP Cookie File\n\
+                    # a comment\n\
+                    \n\
+                    .example.com\tTRUE\t/\tFALSE\t1\told\tgone\n\
+                    example.com\tFALSE\t/\tFALSE\t4102444800\tnew\tkept\n\
+                    example.com\tFALSE\t/\tFALSE\t0\tempty\n\
+                    example.com\tFALSE\t/\n\
+                    example.com\tFALSE\t/\tFALSE\tsoon\tbad\texpiry\n\
+                    ;; not a cookie at all\n\
+                    \tFALSE\t/\tFALSE\t0\tno\tdomain\n\
+                    exam ple.com\tFALSE\t/\tFALSE\t0\tspaced\tdomain\n\
+                    example.com \tFALSE\t/\tFALSE\t0\tlast\tone\n";
+        let jar = CookieJar::new();
+        assert_eq!(jar.read_netscape(file.as_bytes()).unwrap(), 3);
+        let names: Vec<String> = jar.cookies().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["new", "empty", "last"]);
+        assert!(jar.cookies().iter().all(|c| c.domain == "example.com"));
+    }
+
+    #[test]
+    fn save_replaces_the_file_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.txt");
+        fs::write(&path, "example.com\tFALSE\t/\tFALSE\t0\told\tcookie\n").unwrap();
+
+        let jar = CookieJar::new();
+        jar.store_response_cookies(&"http://example.com/".parse().unwrap(), ["new=cookie"]);
+        jar.save(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("\tnew\tcookie"));
+        assert!(!saved.contains("old"));
+        // only the file itself is left, no temp beside it
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
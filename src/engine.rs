@@ -1,171 +1,846 @@
+#[cfg(feature = "async")]
+mod asynchronous;
+mod auth;
+mod batch;
+mod caching;
+mod download;
+mod error;
+mod http;
+mod proxy;
+mod socks;
+mod streaming;
+mod timeout;
+mod tls;
+
 use std::{
-    fs::File,
-    io::{BufReader, Read, Write},
-    net::TcpStream,
+    fs,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use anyhow::Context;
-use rustls::{ClientConfig, Stream};
+#[cfg(feature = "async")]
+pub use asynchronous::{fetch_async, send_async};
+pub use auth::Credentials;
+pub use batch::{Batch, BatchResult};
+use cache::Cache;
+pub use caching::CacheMode;
+pub use download::{Download, DownloadError, Downloaded};
+pub use error::FetchError;
+pub use proxy::{Proxies, Proxy, ProxyKind};
+pub use streaming::{Progress, StreamingResponse};
+pub use timeout::{CancelHandle, TimeoutKind};
+pub use tls::{TlsConfig, TlsError};
 
 use crate::{
-    parser::HttpResponseParser,
-    response::Response,
-    url::{Scheme, URL},
+    cookie::CookieJar,
+    form::Form,
+    netrc::Netrc,
+    request::{Method, Request},
+    response::{Redirect, Response, Source},
+    url::{ParseError, Scheme, URL},
 };
+use caching::{Plan, plan};
+use rustls::ClientConfig;
+use timeout::{Limits, Timeouts};
 
 const MAX_REDIRECTS: u32 = 10;
 
-pub fn fetch(url: &URL) -> anyhow::Result<Response> {
-    let mut response = request(url);
-
-    for _ in 0..MAX_REDIRECTS {
-        if let Ok(Response::Http(ref inner)) = response {
-            let parsed = HttpResponseParser::parse(inner)?;
-            if (300u32..400).contains(&parsed.status()) {
-                let location = parsed
-                    .headers_map()
-                    .get("Location")
-                    .ok_or(anyhow::anyhow!("Missing Location header in 300 response"))?;
-                println!("{}", location);
-
-                //handle relative paths
-                let location = if location.starts_with("/") {
-                    let scheme = url.scheme();
-                    let base_url = url.host().context("relative path; couldnt get base")?;
-                    format!("{}://{}{}", scheme, base_url, location)
-                } else {
-                    location.to_string()
-                };
-
-                println!("{}", location);
-                let new_url: URL = location.parse()?;
-                response = request(&new_url)
-            } else {
-                //got non redirect
-                break;
+pub struct Engine {
+    cache: Option<Arc<Cache>>,
+    cache_mode: CacheMode,
+    cookies: CookieJar,
+    max_redirects: u32,
+    insecure_redirects: bool,
+    timeouts: Timeouts,
+    cancel: CancelHandle,
+    proxies: Proxies,
+    credentials: Option<Credentials>,
+    netrc: Netrc,
+    tls: Arc<ClientConfig>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self {
+            cache: None,
+            cache_mode: CacheMode::default(),
+            cookies: CookieJar::default(),
+            max_redirects: MAX_REDIRECTS,
+            insecure_redirects: false,
+            timeouts: Timeouts::default(),
+            cancel: CancelHandle::default(),
+            proxies: Proxies::default(),
+            credentials: None,
+            netrc: Netrc::default(),
+            tls: tls::default_config(),
+        }
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_redirects(mut self, max_redirects: u32) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Whether to follow redirects from https to plain http, off by default
+    pub fn with_insecure_redirects(mut self, allow: bool) -> Self {
+        self.insecure_redirects = allow;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    pub fn with_cache_mode(mut self, cache_mode: CacheMode) -> Self {
+        self.cache_mode = cache_mode;
+        self
+    }
+
+    /// Serve only what is already cached and never touch the network, what `--offline` selects
+    pub fn offline(self) -> Self {
+        self.with_cache_mode(CacheMode::OnlyIfCached)
+    }
+
+    pub fn with_cookie_jar(mut self, cookies: CookieJar) -> Self {
+        self.cookies = cookies;
+        self
+    }
+
+    /// Limits resolving and connecting to each host
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Limits how long the server can go without sending anything
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Limits how long the server can go without taking any of the request
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// Limits each fetch as a whole, redirects included
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.total = Some(timeout);
+        self
+    }
+
+    /// Fetches fail with `FetchError::Cancelled` once the handle is cancelled
+    pub fn with_cancel_handle(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Sends http and https through this proxy
+    pub fn with_proxy(self, proxy: Proxy) -> Self {
+        self.with_proxies(Proxies::all(proxy))
+    }
+
+    /// Picks a proxy per url, `Proxies::from_env` for what the environment says. Engines
+    /// connect directly unless given one
+    pub fn with_proxies(mut self, proxies: Proxies) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    /// Answers a 401 from the host a fetch starts at, redirects elsewhere dont get them.
+    /// Credentials in the url itself win
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Answers a 401 with the host's login, when there are no other credentials for it
+    pub fn with_netrc(mut self, netrc: Netrc) -> Self {
+        self.netrc = netrc;
+        self
+    }
+
+    /// Replaces the webpki roots only config, see `TlsConfig`. Connections share it
+    pub fn with_tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls = config;
+        self
+    }
+
+    pub fn cancel_handle(&self) -> &CancelHandle {
+        &self.cancel
+    }
+
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_deref()
+    }
+
+    pub fn cookies(&self) -> &CookieJar {
+        &self.cookies
+    }
+
+    pub fn fetch(&self, url: &URL) -> Result<Response, FetchError> {
+        self.send(Request::get(url.clone()))
+    }
+
+    /// Submits a form found on the page at `base`, see `Form::submit`
+    pub fn submit(
+        &self,
+        form: &Form,
+        base: &URL,
+        submitter: Option<&str>,
+    ) -> Result<Response, FetchError> {
+        self.send(form.submit(base, submitter)?)
+    }
+
+    /// Sends a request, following redirects and answering a 401 once when there are
+    /// credentials. The response has the final url and every hop taken to get there.
+    /// The whole body is read into memory, which is what lets it be cached; `fetch` and
+    /// `send_batch` do the same. Use `send_streaming` for bodies too big to hold
+    pub fn send(&self, mut request: Request) -> Result<Response, FetchError> {
+        let limits = Limits::new(self.timeouts, self.cancel.clone());
+        let mut hops = Hops::new(&mut request);
+        loop {
+            limits.check()?;
+            let mut response = self.request(&request, hops.same_site, &limits)?;
+            if let Some(retry) = self.authorize(&hops, &request, &response) {
+                request = retry;
+                response = self.request(&request, hops.same_site, &limits)?;
+            }
+            match self.next_hop(&mut hops, &request, &mut response)? {
+                Some(next) => request = next,
+                None => return Ok(response),
+            }
+        }
+    }
+
+    /// The request following a redirect, or none when this is the final response, which then
+    /// gets the redirects taken and the total time
+    fn next_hop(
+        &self,
+        hops: &mut Hops,
+        request: &Request,
+        response: &mut Response,
+    ) -> Result<Option<Request>, FetchError> {
+        let Some((status, location)) = redirect_location(response) else {
+            response.set_redirects(std::mem::take(&mut hops.redirects));
+            response.timings_mut().total = hops.start.elapsed();
+            return Ok(None);
+        };
+        if hops.redirects.len() >= self.max_redirects as usize {
+            return Err(FetchError::TooManyRedirects(self.max_redirects));
+        }
+
+        let url = request.url().join(&location)?;
+        if let Some(userinfo) = auth::userinfo(&url) {
+            hops.userinfo = Some(userinfo);
+        }
+        let url = url.without_userinfo();
+        if request.url().scheme() == Scheme::Https
+            && url.scheme() == Scheme::Http
+            && !self.insecure_redirects
+        {
+            return Err(FetchError::InsecureRedirect {
+                from: request.url().to_string(),
+                to: url.to_string(),
+            });
+        }
+        hops.redirects.push(Redirect {
+            url: request.url().clone(),
+            status,
+            location: url.clone(),
+        });
+        hops.same_site &= self.cookies.same_site(&hops.first, &url);
+        Ok(Some(redirect_request(request, status, url)))
+    }
+
+    fn request(
+        &self,
+        request: &Request,
+        same_site: bool,
+        limits: &Limits,
+    ) -> Result<Response, FetchError> {
+        let url = request.url();
+        match url.scheme() {
+            Scheme::Http | Scheme::Https => self.request_cached(request, same_site, limits),
+            Scheme::File => request_file(url),
+            Scheme::Data => request_data(url),
+            Scheme::ViewSource => {
+                let response = self.request(&view_source_request(request)?, same_site, limits)?;
+                Ok(into_view_source(response, url))
             }
-        } else {
-            //non http
-            break;
+            Scheme::Unknown => Err(FetchError::UnsupportedScheme(url.to_string())),
         }
     }
 
-    //if still redirecting
-    if let Ok(Response::Http(ref inner)) = response {
-        let parsed = HttpResponseParser::parse(inner)?;
-        if (300u32..400).contains(&parsed.status()) {
-            return Err(anyhow::anyhow!("Too many redirects"));
+    fn request_cached(
+        &self,
+        request: &Request,
+        same_site: bool,
+        limits: &Limits,
+    ) -> Result<Response, FetchError> {
+        match plan(self.cache.as_ref(), self.cache_mode, request)? {
+            Plan::Cached(response) => Ok(*response),
+            Plan::Network(update) => {
+                let response = self.request_network(request, same_site, limits)?;
+                if let Some(update) = update {
+                    update.apply(request, &response)?;
+                }
+                Ok(response)
+            }
         }
     }
-    response
+
+    /// Sends the jar's cookies for the url and stores whatever the response sets
+    fn request_network(
+        &self,
+        request: &Request,
+        same_site: bool,
+        limits: &Limits,
+    ) -> Result<Response, FetchError> {
+        let url = request.url();
+        let cookie = self.cookies.cookie_header(url, same_site);
+        let proxy = self.proxies.for_url(url);
+        let response = http::request(request, cookie.as_deref(), proxy, &self.tls, limits)?;
+        self.cookies
+            .store_response_cookies(url, response.header_values("Set-Cookie"));
+        Ok(response)
+    }
 }
-fn request(url: &URL) -> anyhow::Result<Response> {
-    match url.scheme() {
-        Scheme::Http => request_http(url),
-        Scheme::Https => request_https(url),
-        Scheme::File => request_file(url),
-        Scheme::Data => request_data(url),
-        Scheme::ViewSource => request_view_source(url),
-        Scheme::Unknown => Err(anyhow::anyhow!("Cannot request unknown/unsupported scheme")),
+
+/// Where a fetch has got to while following redirects
+struct Hops {
+    start: Instant,
+    first: URL,
+    // once a redirect leaves the site, SameSite=Strict cookies stay behind for the rest
+    same_site: bool,
+    redirects: Vec<Redirect>,
+    // credentials from `user:password@` and the host they are for, taken out of the url so
+    // they dont end up in the cache, responses or errors
+    userinfo: Option<(String, Credentials)>,
+}
+
+impl Hops {
+    fn new(request: &mut Request) -> Self {
+        let userinfo = auth::userinfo(request.url());
+        let url = request.url().without_userinfo();
+        request.set_url(url);
+        Self {
+            start: Instant::now(),
+            first: request.url().clone(),
+            same_site: true,
+            redirects: Vec::new(),
+            userinfo,
+        }
     }
 }
 
-fn request_data(url: &URL) -> anyhow::Result<Response> {
-    let data = url
-        .data()
-        .ok_or(anyhow::anyhow!("no data- should not happen"))?;
-    Ok(Response::Data(data.to_string()))
+pub fn fetch(url: &URL) -> Result<Response, FetchError> {
+    Engine::new().fetch(url)
 }
-fn request_file(url: &URL) -> anyhow::Result<Response> {
-    let path = url
-        .path()
-        .ok_or(anyhow::anyhow!("missing path in file url"))?;
-    println!("{}", path);
-    let file = File::open(path)?;
-
-    let mut bufread = BufReader::new(file);
-    let mut contents = String::new();
-    bufread.read_to_string(&mut contents)?;
-
-    Ok(Response::File(contents))
+
+pub fn send(request: Request) -> Result<Response, FetchError> {
+    Engine::new().send(request)
 }
 
-fn request_view_source(url: &URL) -> anyhow::Result<Response> {
-    let underlying_url: URL = url
-        .data()
-        .ok_or(anyhow::anyhow!("no data in view-source url"))?
-        .parse()?;
-    let res = request(&underlying_url)?;
-    Ok(Response::ViewSource(Box::new(res)))
+/// The status and Location of a response that should be followed. 300 and 304 arent followed,
+/// and neither is a redirect without a Location
+fn redirect_location(response: &Response) -> Option<(u32, String)> {
+    if response.source() != Source::Http
+        || !matches!(response.status(), 301 | 302 | 303 | 307 | 308)
+    {
+        return None;
+    }
+    let location = response.header("Location")?;
+    Some((response.status(), location.to_string()))
 }
 
-fn request_http(url: &URL) -> anyhow::Result<Response> {
-    let host = url
-        .host()
-        .ok_or(anyhow::anyhow!("missing host in http request"))?;
-
-    let path = url
-        .path()
-        .ok_or(anyhow::anyhow!("missing path in http request"))?;
-    let mut stream = TcpStream::connect((host, 80))?;
-
-    let request = format!(
-        concat!(
-            "GET {} HTTP/1.1\r\n",
-            "Host: {}\r\n",
-            "Connection: close\r\n",
-            "User-Agent: browser_rust\r\n",
-            "\r\n"
-        ),
-        path, host
-    );
-
-    println!("{}", &request);
-
-    let _ = stream.write(request.as_bytes());
-    let mut bufread = BufReader::new(&stream);
-    let mut buffer = String::new();
-    bufread.read_to_string(&mut buffer)?;
-    Ok(Response::Http(buffer))
+/// The request to send to a redirect's location, following the Fetch spec: a POST redirected by
+/// 301 or 302, or anything but GET and HEAD redirected by 303, becomes a bodyless GET, while 307
+/// and 308 keep the method and body. Credentials set on the request dont follow it to another
+/// origin, the jar decides which cookies go there
+fn redirect_request(request: &Request, status: u32, url: URL) -> Request {
+    let method = request.method();
+    let to_get = (matches!(status, 301 | 302) && method == Method::Post)
+        || (status == 303 && !matches!(method, Method::Get | Method::Head));
+    let cross_origin = !same_origin(request.url(), &url);
+
+    let mut redirected = Request::new(if to_get { Method::Get } else { method }, url);
+    for (name, value) in request.headers() {
+        let body_header = [
+            "Content-Type",
+            "Content-Encoding",
+            "Content-Language",
+            "Content-Location",
+        ]
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name));
+        let credential = ["Authorization", "Proxy-Authorization", "Cookie"]
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name));
+        if to_get && body_header || cross_origin && credential {
+            continue;
+        }
+        redirected = redirected.with_header(name, value);
+    }
+    if to_get {
+        redirected
+    } else {
+        redirected.with_body(request.body())
+    }
+}
+
+fn same_origin(a: &URL, b: &URL) -> bool {
+    let port = |url: &URL| {
+        url.port().or(match url.scheme() {
+            Scheme::Http => Some(80),
+            Scheme::Https => Some(443),
+            _ => None,
+        })
+    };
+    a.scheme() == b.scheme()
+        && a.hostname().map(str::to_ascii_lowercase) == b.hostname().map(str::to_ascii_lowercase)
+        && port(a) == port(b)
+}
+
+/// The request for the url inside a view-source url
+fn view_source_request(request: &Request) -> Result<Request, FetchError> {
+    let underlying_url: URL = request.url().data().ok_or(ParseError::Empty)?.parse()?;
+    let mut underlying = Request::new(request.method(), underlying_url);
+    for (name, value) in request.headers() {
+        underlying = underlying.with_header(name, value);
+    }
+    Ok(underlying.with_body(request.body()))
+}
+
+fn into_view_source(mut response: Response, url: &URL) -> Response {
+    response.set_url(url.clone());
+    response.set_source(Source::ViewSource);
+    response
+}
+
+fn request_data(url: &URL) -> Result<Response, FetchError> {
+    let data = url.data().ok_or(ParseError::Empty)?;
+    let (media_type, body) = data.split_once(',').unwrap_or(("", data));
+    let mut response = Response::new(url.clone(), Source::Data, body);
+    // an empty media type means plain ascii text
+    if media_type.is_empty() {
+        response.set_header("Content-Type", "text/plain;charset=US-ASCII");
+    } else {
+        response.set_header("Content-Type", media_type);
+    }
+    Ok(response)
 }
 
-fn request_https(url: &URL) -> anyhow::Result<Response> {
-    let host = url
-        .host()
-        .ok_or(anyhow::anyhow!("missing host in https request"))?;
+fn request_file(url: &URL) -> Result<Response, FetchError> {
+    let path = url.path().ok_or(ParseError::PathMissing)?;
+    let body = fs::read(path).map_err(|source| FetchError::File {
+        path: path.to_string(),
+        source,
+    })?;
+    Ok(Response::new(url.clone(), Source::File, body))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    const FRESH: &str = "HTTP/1.1 200 OK\r\nCache-Control: max-age=3600\r\n\r\n<p>fresh</p>";
+    const STALE: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>stale</p>";
+
+    // nothing listens on port 1 locally, so any network use fails straight away
+    fn unreachable_url() -> URL {
+        "http://127.0.0.1:1/page".parse().unwrap()
+    }
+
+    fn engine_with(url: &URL, response: &str, mode: CacheMode) -> Engine {
+        let cache = Cache::in_memory();
+        cache
+            .save(&mut caching::CacheKey::new(url), response)
+            .unwrap();
+        Engine::new().with_cache(cache).with_cache_mode(mode)
+    }
+
+    #[test]
+    fn offline_serves_stale_entries() {
+        let url = unreachable_url();
+        let engine = engine_with(&url, STALE, CacheMode::OnlyIfCached);
+        let response = engine.fetch(&url).unwrap();
+        assert!(response.from_cache());
+        assert_eq!(response.to_http(), STALE.as_bytes());
+    }
+
+    #[test]
+    fn offline_without_entry_is_not_cached() {
+        let engine = Engine::new().with_cache(Cache::in_memory()).offline();
+        let err = engine.fetch(&unreachable_url()).err().unwrap();
+        assert!(matches!(err, FetchError::NotCached { .. }));
+
+        let err = Engine::new()
+            .offline()
+            .fetch(&unreachable_url())
+            .err()
+            .unwrap();
+        assert!(matches!(err, FetchError::NotCached { .. }));
+    }
+
+    #[test]
+    fn default_mode_serves_only_fresh_entries() {
+        let url = unreachable_url();
+        let engine = engine_with(&url, FRESH, CacheMode::Default);
+        assert!(engine.fetch(&url).is_ok());
+
+        let engine = engine_with(&url, STALE, CacheMode::Default);
+        assert!(engine.fetch(&url).is_err());
+    }
+
+    /// Serves one canned response per connection on a local port, handing back each raw
+    /// request received
+    pub(crate) fn serve(responses: Vec<String>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                requests.push(read_request(&mut stream));
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (base, handle)
+    }
+
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+            request.push(byte[0]);
+        }
+        let head = String::from_utf8(request).unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        head + &String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn sends_method_headers_and_body() {
+        let (base, server) = serve(vec![
+            "HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok".to_string(),
+        ]);
+        let request = Request::put(format!("{}/items/1", base).parse().unwrap())
+            .with_header("Content-Type", "application/json")
+            .with_body(r#"{"a":1}"#);
+        let response = send(request).unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.reason(), "Created");
+        assert_eq!(response.body(), b"ok");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("PUT /items/1 HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Content-Type: application/json\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{\"a\":1}"));
+    }
+
+    #[test]
+    fn goes_through_proxies() {
+        let (proxy, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nproxied".to_string(),
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_string(),
+            "HTTP/1.1 200 Connection established\r\n\r\n".to_string(),
+        ]);
+        let proxy: Proxy = proxy.parse().unwrap();
+        let engine = Engine::new().with_proxy(proxy.clone().with_credentials("user", "pass"));
+        let response = engine
+            .fetch(&"http://example.com/page?q=1".parse().unwrap())
+            .unwrap();
+        assert_eq!(response.text(), "proxied");
+
+        let secure: URL = "https://example.com/".parse().unwrap();
+        let err = engine.fetch(&secure).err().unwrap();
+        assert!(matches!(err, FetchError::ProxyTunnel { status: 407, .. }));
+        // the tunnel opens, but the proxy hangs up instead of passing on any tls
+        let err = Engine::new()
+            .with_proxy(proxy)
+            .fetch(&secure)
+            .err()
+            .unwrap();
+        assert!(!matches!(err, FetchError::ProxyTunnel { .. }));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET http://example.com/page?q=1 HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Host: example.com\r\n"));
+        assert!(requests[0].contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+        assert!(requests[1].starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(requests[1].contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+        assert!(!requests[2].contains("Proxy-Authorization"));
+    }
+
+    #[test]
+    fn cookies_follow_redirects() {
+        let (base, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /next\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\n"
+                .to_string(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string(),
+        ]);
+        let engine = Engine::new();
+        engine
+            .fetch(&format!("{}/", base).parse().unwrap())
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("Cookie:"));
+        assert!(requests[1].starts_with("GET /next HTTP/1.1\r\n"));
+        assert!(requests[1].contains("Cookie: a=1; b=2\r\n"));
+    }
 
-    let path = url
-        .path()
-        .ok_or(anyhow::anyhow!("missing path in https request"))?;
+    #[test]
+    fn redirect_methods_and_bodies() {
+        let redirect = |status: &str| {
+            format!(
+                "HTTP/1.1 {}\r\nLocation: /next\r\nContent-Length: 0\r\n\r\n",
+                status
+            )
+        };
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string();
+        let (base, server) = serve(vec![
+            redirect("303 See Other"),
+            ok.clone(),
+            redirect("307 Temporary Redirect"),
+            ok.clone(),
+            redirect("301 Moved Permanently"),
+            ok,
+        ]);
+        let post = || {
+            Request::post(format!("{}/form", base).parse().unwrap())
+                .with_header("Content-Type", "text/plain")
+                .with_body("data")
+        };
+        let engine = Engine::new();
+        let response = engine.send(post()).unwrap();
+        let redirects = response.redirects();
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0].status, 303);
+        assert_eq!(redirects[0].location.to_string(), format!("{}/next", base));
+        engine.send(post()).unwrap();
+        engine
+            .send(Request::put(format!("{}/form", base).parse().unwrap()).with_body("data"))
+            .unwrap();
 
-    let root_store =
-        rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with("GET /next"));
+        assert!(!requests[1].contains("Content-Type") && !requests[1].ends_with("data"));
+        assert!(requests[3].starts_with("POST /next"));
+        assert!(requests[3].contains("Content-Type: text/plain") && requests[3].ends_with("data"));
+        // only POST is rewritten by a 301
+        assert!(requests[5].starts_with("PUT /next") && requests[5].ends_with("data"));
+    }
 
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    #[test]
+    fn not_modified_and_multiple_choices_arent_followed() {
+        let (base, server) = serve(vec![
+            "HTTP/1.1 304 Not Modified\r\nETag: x\r\n\r\n".to_string(),
+            "HTTP/1.1 300 Multiple Choices\r\nLocation: /a\r\n\r\n".to_string(),
+        ]);
+        let url: URL = format!("{}/", base).parse().unwrap();
+        assert_eq!(fetch(&url).unwrap().status(), 304);
+        assert_eq!(fetch(&url).unwrap().status(), 300);
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
 
-    let rc_config = Arc::new(config);
-    let mut client = rustls::ClientConnection::new(rc_config, host.to_string().try_into()?)?;
-    let mut socket = TcpStream::connect((host, 443))?;
-    let mut stream = Stream::new(&mut client, &mut socket);
+    #[test]
+    fn credentials_dont_cross_origins() {
+        let (other, other_server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string(),
+        ]);
+        let (base, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /same\r\n\r\n".to_string(),
+            format!("HTTP/1.1 302 Found\r\nLocation: {}/other\r\n\r\n", other),
+        ]);
+        let request = Request::get(format!("{}/", base).parse().unwrap())
+            .with_header("Authorization", "Bearer secret")
+            .with_header("Cookie", "session=1")
+            .with_header("Accept", "text/html");
+        let response = send(request).unwrap();
+        assert_eq!(response.redirects().len(), 2);
+        assert_eq!(response.url().to_string(), format!("{}/other", other));
+
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("Authorization: Bearer secret"));
+        assert!(requests[1].contains("Cookie: session=1"));
+        let requests = other_server.join().unwrap();
+        assert!(!requests[0].contains("Authorization"));
+        assert!(!requests[0].contains("Cookie"));
+        assert!(requests[0].contains("Accept: text/html"));
+    }
 
-    stream.write_all(
-        format!(
+    #[test]
+    fn refuses_downgrades_and_loops() {
+        let secure: URL = "https://example.com/".parse().unwrap();
+        let engine = engine_with(
+            &secure,
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: http://example.com/\r\n\r\n",
+            CacheMode::OnlyIfCached,
+        );
+        let err = engine.fetch(&secure).err().unwrap();
+        assert!(matches!(err, FetchError::InsecureRedirect { .. }));
+        // allowed, it then fails as the http url isnt cached
+        let err = engine
+            .with_insecure_redirects(true)
+            .fetch(&secure)
+            .err()
+            .unwrap();
+        assert!(matches!(err, FetchError::NotCached { .. }));
+
+        let url = unreachable_url();
+        let engine = engine_with(
+            &url,
+            "HTTP/1.1 302 Found\r\nLocation: /page\r\n\r\n",
+            CacheMode::OnlyIfCached,
+        );
+        let err = engine.fetch(&url).err().unwrap();
+        assert!(matches!(err, FetchError::TooManyRedirects(MAX_REDIRECTS)));
+    }
+
+    #[test]
+    fn response_has_final_url_status_headers_and_timings() {
+        let (base, server) = serve(vec![
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /final\r\n\r\n".to_string(),
             concat!(
-                "GET {} HTTP/1.1\r\n",
-                "Host: {}\r\n",
-                "Connection: close\r\n",
-                "User-Agent: browser_rust\r\n",
-                "\r\n"
-            ),
-            path, host
-        )
-        .as_bytes(),
-    )?;
-
-    let mut buffer = String::new();
-    let _ = stream.read_to_string(&mut buffer);
-    Ok(Response::Http(buffer))
+                "HTTP/1.1 404 Not Found\r\n",
+                "Content-Type: text/plain\r\n",
+                "Transfer-Encoding: chunked\r\n",
+                "\r\n",
+                "4\r\ngone\r\n0\r\n\r\n"
+            )
+            .to_string(),
+        ]);
+        let url: URL = format!("{}/start", base).parse().unwrap();
+        let response = fetch(&url).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(response.url().to_string(), format!("{}/final", base));
+        assert_eq!(response.source(), Source::Http);
+        assert!(!response.from_cache());
+        assert_eq!((response.status(), response.reason()), (404, "Not Found"));
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.text(), "gone");
+        assert_eq!(
+            response.redirects(),
+            [Redirect {
+                url,
+                status: 301,
+                location: response.url().clone(),
+            }]
+        );
+        let timings = response.timings();
+        assert_eq!(timings.tls, std::time::Duration::ZERO);
+        assert!(timings.total >= timings.ttfb + timings.download);
+    }
+
+    #[test]
+    fn data_and_view_source() {
+        let response = fetch(&"data:text/html,<p>hi</p>".parse().unwrap()).unwrap();
+        assert_eq!(response.source(), Source::Data);
+        assert_eq!(response.header("Content-Type"), Some("text/html"));
+        assert_eq!(response.text(), "<p>hi</p>");
+
+        let url: URL = "view-source:data:text/html,<p>hi</p>".parse().unwrap();
+        let response = fetch(&url).unwrap();
+        assert_eq!(response.source(), Source::ViewSource);
+        assert_eq!(response.url(), &url);
+        assert_eq!(response.text(), "<p>hi</p>");
+    }
+
+    #[test]
+    fn errors_say_what_failed() {
+        let err = fetch(&unreachable_url()).err().unwrap();
+        assert!(matches!(err, FetchError::Connect { port: 1, .. }));
+        assert!(std::error::Error::source(&err).is_some());
+
+        let (base, server) = serve(vec!["garbage".to_string()]);
+        let err = fetch(&format!("{}/", base).parse().unwrap()).err().unwrap();
+        assert!(matches!(err, FetchError::Protocol(_)));
+        server.join().unwrap();
+    }
+
+    /// Accepts one connection, reads the request and then says nothing for a second
+    pub(crate) fn hang() -> (URL, std::thread::JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut stream);
+            std::thread::sleep(Duration::from_secs(1));
+        });
+        (url.parse().unwrap(), handle)
+    }
+
+    #[test]
+    fn read_and_total_timeouts() {
+        let (url, server) = hang();
+        let engine = Engine::new().with_read_timeout(Duration::from_millis(100));
+        let started = Instant::now();
+        let err = engine.fetch(&url).err().unwrap();
+        assert!(matches!(err, FetchError::Timeout(TimeoutKind::Read)));
+        assert!(started.elapsed() < Duration::from_millis(900));
+        server.join().unwrap();
+
+        let (url, server) = hang();
+        let engine = Engine::new()
+            .with_read_timeout(Duration::from_secs(5))
+            .with_timeout(Duration::from_millis(100));
+        let err = engine.fetch(&url).err().unwrap();
+        assert!(matches!(err, FetchError::Timeout(TimeoutKind::Total)));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_named() {
+        let err = fetch(&"file:///no/such/file.html".parse().unwrap())
+            .err()
+            .unwrap();
+        assert!(matches!(err, FetchError::File { ref path, .. } if path == "/no/such/file.html"));
+        assert_eq!(err.to_string(), "couldnt read /no/such/file.html");
+    }
+
+    #[test]
+    fn cancel_from_another_thread() {
+        let (url, server) = hang();
+        let engine = Engine::new();
+        let cancel = engine.cancel_handle().clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        let err = engine.fetch(&url).err().unwrap();
+        assert!(matches!(err, FetchError::Cancelled));
+        assert!(started.elapsed() < Duration::from_millis(900));
+        canceller.join().unwrap();
+        server.join().unwrap();
+
+        // a cancelled handle stops fetches before they start
+        let err = engine.fetch(&"data:,hi".parse().unwrap()).err().unwrap();
+        assert!(matches!(err, FetchError::Cancelled));
+
+        engine.cancel_handle().reset();
+        assert!(engine.fetch(&"data:,hi".parse().unwrap()).is_ok());
+    }
 }
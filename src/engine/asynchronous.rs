@@ -0,0 +1,494 @@
+//! The same fetches over tokio sockets, for the `async` feature. Only the io differs from the
+//! blocking engine, redirects, caching, cookies and parsing are shared
+
+use std::{future::Future, io, pin::Pin, time::Instant};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, lookup_host},
+};
+use tokio_rustls::TlsConnector;
+
+use super::{
+    Engine, FetchError, Hops,
+    caching::{Plan, plan},
+    http::{connect_error, read_response, server_name},
+    into_view_source,
+    proxy::{Proxy, ProxyKind, check_tunnel, connect_head},
+    request_data, socks,
+    timeout::{Limits, POLL, TimeoutKind},
+    view_source_request,
+};
+use crate::{
+    request::Request,
+    response::{Response, Source, Timings},
+    url::{ParseError, Scheme, URL},
+};
+
+impl Engine {
+    pub async fn fetch_async(&self, url: &URL) -> Result<Response, FetchError> {
+        self.send_async(Request::get(url.clone())).await
+    }
+
+    /// `send` without blocking the thread
+    pub async fn send_async(&self, mut request: Request) -> Result<Response, FetchError> {
+        let limits = Limits::new(self.timeouts, self.cancel.clone());
+        let mut hops = Hops::new(&mut request);
+        loop {
+            limits.check()?;
+            let mut response = self
+                .request_async(&request, hops.same_site, &limits)
+                .await?;
+            if let Some(retry) = self.authorize(&hops, &request, &response) {
+                request = retry;
+                response = self
+                    .request_async(&request, hops.same_site, &limits)
+                    .await?;
+            }
+            match self.next_hop(&mut hops, &request, &mut response)? {
+                Some(next) => request = next,
+                None => return Ok(response),
+            }
+        }
+    }
+
+    // boxed as view-source urls recurse
+    fn request_async<'a>(
+        &'a self,
+        request: &'a Request,
+        same_site: bool,
+        limits: &'a Limits,
+    ) -> Pin<Box<dyn Future<Output = Result<Response, FetchError>> + Send + 'a>> {
+        Box::pin(async move {
+            let url = request.url();
+            match url.scheme() {
+                Scheme::Http | Scheme::Https => {
+                    let (cache, mode, planned) =
+                        (self.cache.clone(), self.cache_mode, request.clone());
+                    match unblocked(move || plan(cache.as_ref(), mode, &planned)).await? {
+                        Plan::Cached(response) => Ok(*response),
+                        Plan::Network(update) => {
+                            let response = self
+                                .request_network_async(request, same_site, limits)
+                                .await?;
+                            let Some(update) = update else {
+                                return Ok(response);
+                            };
+                            let request = request.clone();
+                            unblocked(move || {
+                                update.apply(&request, &response)?;
+                                Ok(response)
+                            })
+                            .await
+                        }
+                    }
+                }
+                Scheme::File => {
+                    let path = url.path().ok_or(ParseError::PathMissing)?;
+                    let body = tokio::fs::read(path)
+                        .await
+                        .map_err(|source| FetchError::File {
+                            path: path.to_string(),
+                            source,
+                        })?;
+                    Ok(Response::new(url.clone(), Source::File, body))
+                }
+                Scheme::Data => request_data(url),
+                Scheme::ViewSource => {
+                    let underlying = view_source_request(request)?;
+                    let response = self.request_async(&underlying, same_site, limits).await?;
+                    Ok(into_view_source(response, url))
+                }
+                Scheme::Unknown => Err(FetchError::UnsupportedScheme(url.to_string())),
+            }
+        })
+    }
+
+    async fn request_network_async(
+        &self,
+        request: &Request,
+        same_site: bool,
+        limits: &Limits,
+    ) -> Result<Response, FetchError> {
+        let url = request.url();
+        let cookie = self.cookies.cookie_header(url, same_site);
+        let mut head = request.wire_head(cookie.as_deref())?;
+        let host = url.hostname().ok_or(ParseError::HostMissing)?;
+        let https = url.scheme() == Scheme::Https;
+        let port = url.port().unwrap_or(if https { 443 } else { 80 }) as u16;
+        let mut timings = Timings::default();
+
+        let mut socket = match self.proxies.for_url(url) {
+            Some(proxy) => {
+                let mut socket = connect(proxy.host(), proxy.port(), limits, &mut timings).await?;
+                match proxy.kind() {
+                    ProxyKind::Http if https => {
+                        tunnel(&mut socket, url, port, proxy, limits).await?
+                    }
+                    ProxyKind::Http => {
+                        head = request
+                            .proxy_wire_head(cookie.as_deref(), proxy.authorization().as_deref())?;
+                    }
+                    ProxyKind::Socks5 | ProxyKind::Socks5h => {
+                        socks_handshake(&mut socket, host, port, proxy, limits, &mut timings)
+                            .await?
+                    }
+                }
+                socket
+            }
+            None => connect(host, port, limits, &mut timings).await?,
+        };
+
+        let message = if https {
+            let server_name = server_name(host)?;
+            let start = Instant::now();
+            let connector = TlsConnector::from(self.tls.clone());
+            let mut stream = limited(
+                limits,
+                TimeoutKind::Read,
+                limits.timeouts.read,
+                connector.connect(server_name, socket),
+            )
+            .await?;
+            timings.tls = start.elapsed();
+            exchange(&mut stream, &head, request.body(), limits, &mut timings).await?
+        } else {
+            exchange(&mut socket, &head, request.body(), limits, &mut timings).await?
+        };
+        let response = read_response(request, &message, timings)?;
+        self.cookies
+            .store_response_cookies(url, response.header_values("Set-Cookie"));
+        Ok(response)
+    }
+}
+
+pub async fn fetch_async(url: &URL) -> Result<Response, FetchError> {
+    Engine::new().fetch_async(url).await
+}
+
+pub async fn send_async(request: Request) -> Result<Response, FetchError> {
+    Engine::new().send_async(request).await
+}
+
+/// Awaits a socket operation, checking for cancellation and the overall deadline every `POLL`,
+/// until it finishes or `timeout` runs out
+async fn limited<T, E>(
+    limits: &Limits,
+    kind: TimeoutKind,
+    timeout: Option<std::time::Duration>,
+    op: impl Future<Output = Result<T, E>>,
+) -> Result<T, FetchError>
+where
+    FetchError: From<E>,
+{
+    let mut op = std::pin::pin!(op);
+    let start = Instant::now();
+    loop {
+        limits.check()?;
+        match tokio::time::timeout(POLL, &mut op).await {
+            Ok(result) => return Ok(result?),
+            Err(_) => {
+                if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    return Err(FetchError::Timeout(kind));
+                }
+            }
+        }
+    }
+}
+
+/// Resolves and connects, trying each address in turn
+async fn connect(
+    host: &str,
+    port: u16,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<TcpStream, FetchError> {
+    let connecting = async {
+        let start = Instant::now();
+        let addrs: Vec<_> = lookup_host((host, port))
+            .await
+            .map_err(|source| FetchError::Dns {
+                host: host.to_string(),
+                source,
+            })?
+            .collect();
+        timings.dns = start.elapsed();
+
+        let start = Instant::now();
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => {
+                    timings.connect = start.elapsed();
+                    return Ok(stream);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(connect_error(host, port, last_err))
+    };
+    limited(
+        limits,
+        TimeoutKind::Connect,
+        limits.timeouts.connect,
+        connecting,
+    )
+    .await
+}
+
+/// Asks the proxy for a tunnel to the server, reading the answer a byte at a time so none of
+/// the server's tls is taken with it
+async fn tunnel(
+    socket: &mut TcpStream,
+    url: &URL,
+    port: u16,
+    proxy: &Proxy,
+    limits: &Limits,
+) -> Result<(), FetchError> {
+    let host = url.hostname().ok_or(ParseError::HostMissing)?;
+    let connect = connect_head(host, port, proxy);
+    write_all(socket, connect.as_bytes(), limits).await?;
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let byte = limited(
+            limits,
+            TimeoutKind::Read,
+            limits.timeouts.read,
+            socket.read_u8(),
+        )
+        .await?;
+        head.push(byte);
+    }
+    check_tunnel(url, proxy, &head, &[])
+}
+
+/// `socks::handshake` over a tokio socket
+async fn socks_handshake(
+    socket: &mut TcpStream,
+    host: &str,
+    port: u16,
+    proxy: &Proxy,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<(), FetchError> {
+    let target = match proxy.kind() {
+        ProxyKind::Socks5 => {
+            let start = Instant::now();
+            let resolving = async {
+                lookup_host((host, port))
+                    .await
+                    .map_err(|source| FetchError::Dns {
+                        host: host.to_string(),
+                        source,
+                    })?
+                    .next()
+                    .ok_or_else(|| connect_error(host, port, None))
+            };
+            let addr = limited(
+                limits,
+                TimeoutKind::Connect,
+                limits.timeouts.connect,
+                resolving,
+            )
+            .await?;
+            timings.dns = start.elapsed();
+            socks::Target::Ip(addr.ip())
+        }
+        _ => socks::Target::Domain(host),
+    };
+
+    let mut handshake = socks::Handshake::new(proxy, target, port);
+    let mut step = handshake.start();
+    while let socks::Step::Exchange { send, read } = step {
+        write_all(socket, &send, limits).await?;
+        let mut reply = vec![0; read];
+        read_exact(socket, &mut reply, limits).await?;
+        step = handshake.advance(&reply)?;
+    }
+    Ok(())
+}
+
+/// The cache works with files, locks and fsyncs, so it runs where blocking is fine rather than
+/// holding up the runtime's threads
+async fn unblocked<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, FetchError> + Send + 'static,
+) -> Result<T, FetchError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|err| FetchError::Io(io::Error::other(err)))?
+}
+
+async fn write_all(socket: &mut TcpStream, data: &[u8], limits: &Limits) -> Result<(), FetchError> {
+    limited(
+        limits,
+        TimeoutKind::Write,
+        limits.timeouts.write,
+        socket.write_all(data),
+    )
+    .await
+}
+
+async fn read_exact(
+    socket: &mut TcpStream,
+    buf: &mut [u8],
+    limits: &Limits,
+) -> Result<(), FetchError> {
+    limited(
+        limits,
+        TimeoutKind::Read,
+        limits.timeouts.read,
+        socket.read_exact(buf),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Writes the request and reads until the server closes the connection
+async fn exchange(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    head: &str,
+    body: &[u8],
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, FetchError> {
+    for data in [head.as_bytes(), body] {
+        limited(
+            limits,
+            TimeoutKind::Write,
+            limits.timeouts.write,
+            stream.write_all(data),
+        )
+        .await?;
+    }
+    limited(
+        limits,
+        TimeoutKind::Write,
+        limits.timeouts.write,
+        stream.flush(),
+    )
+    .await?;
+
+    let sent = Instant::now();
+    let mut message = Vec::new();
+    let mut buffer = [0; 8192];
+    let mut first_byte = None;
+    loop {
+        let reading = async {
+            match stream.read(&mut buffer).await {
+                // plenty of servers close tls connections without a close_notify
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof && !message.is_empty() =>
+                {
+                    Ok(0)
+                }
+                result => result,
+            }
+        };
+        let read = limited(limits, TimeoutKind::Read, limits.timeouts.read, reading).await?;
+        if read == 0 {
+            break;
+        }
+        first_byte.get_or_insert_with(Instant::now);
+        message.extend_from_slice(&buffer[..read]);
+    }
+    let first_byte = first_byte.unwrap_or_else(Instant::now);
+    timings.ttfb = first_byte - sent;
+    timings.download = first_byte.elapsed();
+    Ok(message)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::engine::{
+        socks::test::stand_in,
+        test::{hang, serve},
+    };
+
+    #[tokio::test]
+    async fn follows_redirects_with_cookies() {
+        let (base, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /next\r\nSet-Cookie: a=1\r\n\r\n".to_string(),
+            concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "Transfer-Encoding: chunked\r\n",
+                "\r\n",
+                "2\r\nok\r\n0\r\n\r\n"
+            )
+            .to_string(),
+        ]);
+        let url: URL = format!("{}/", base).parse().unwrap();
+        // spawned, so the future has to be Send
+        let engine = Arc::new(Engine::new());
+        let response = tokio::spawn({
+            let engine = engine.clone();
+            async move { engine.fetch_async(&url).await }
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(response.url().to_string(), format!("{}/next", base));
+        assert_eq!(response.redirects().len(), 1);
+        assert_eq!(response.text(), "ok");
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("Cookie: a=1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn goes_through_proxies() {
+        let (proxy, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nproxied".to_string(),
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_string(),
+        ]);
+        let engine = Engine::new().with_proxy(proxy.parse().unwrap());
+        let response = engine
+            .fetch_async(&"http://example.com/".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.text(), "proxied");
+        let err = engine
+            .fetch_async(&"https://example.com/".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, FetchError::ProxyTunnel { status: 407, .. }));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET http://example.com/ HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_socks() {
+        let (port, proxy) = stand_in(
+            Some(("alice", "secret")),
+            "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsocked",
+        );
+        let engine = Engine::new()
+            .with_proxy(Proxy::socks5h("127.0.0.1", port).with_credentials("alice", "secret"));
+        let response = engine
+            .fetch_async(&"http://unresolvable.invalid/".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.text(), "socked");
+        let seen = proxy.join().unwrap().unwrap();
+        assert_eq!(seen.target, "unresolvable.invalid:80");
+    }
+
+    #[tokio::test]
+    async fn times_out_and_views_source() {
+        let (url, server) = hang();
+        let engine = Engine::new().with_read_timeout(Duration::from_millis(100));
+        let err = engine.fetch_async(&url).await.err().unwrap();
+        assert!(matches!(err, FetchError::Timeout(TimeoutKind::Read)));
+        server.join().unwrap();
+
+        let url: URL = "view-source:data:text/html,<p>hi</p>".parse().unwrap();
+        let response = fetch_async(&url).await.unwrap();
+        assert_eq!(response.source(), Source::ViewSource);
+        assert_eq!(response.text(), "<p>hi</p>");
+    }
+}
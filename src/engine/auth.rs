@@ -0,0 +1,437 @@
+//! Answering `401 Unauthorized` with Basic, Digest (RFC 7616) or Bearer credentials
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use super::{Engine, Hops};
+use crate::{
+    request::Request,
+    response::Response,
+    url::{URL, percent_decode},
+};
+
+/// What to answer a server's challenge with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// For Basic or Digest, whichever the server asks for
+    Password {
+        user: String,
+        password: String,
+    },
+    Bearer(String),
+}
+
+impl Credentials {
+    pub fn password(user: impl Into<String>, password: impl Into<String>) -> Self {
+        Credentials::Password {
+            user: user.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn bearer(token: impl Into<String>) -> Self {
+        Credentials::Bearer(token.into())
+    }
+}
+
+/// One challenge from `WWW-Authenticate`, its scheme lowercased
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Challenge {
+    scheme: String,
+    params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Engine {
+    /// The request again with an `Authorization` header, when the response is a 401 with a
+    /// challenge there are credentials for. Url userinfo comes first, then the engine's own
+    /// credentials, which only go to the host the fetch started at, then `.netrc`
+    pub(super) fn authorize(
+        &self,
+        hops: &Hops,
+        request: &Request,
+        response: &Response,
+    ) -> Option<Request> {
+        if response.status() != 401 {
+            return None;
+        }
+        let credentials = self.credentials_for(hops, request.url())?;
+        let challenges: Vec<Challenge> = response
+            .header_values("WWW-Authenticate")
+            .flat_map(parse_challenges)
+            .collect();
+        let authorization = authorization(&credentials, &challenges, request)?;
+        let mut retry = request.clone();
+        retry.set_header("Authorization", authorization);
+        Some(retry)
+    }
+
+    fn credentials_for(&self, hops: &Hops, url: &URL) -> Option<Credentials> {
+        if let Some((host, credentials)) = &hops.userinfo
+            && url.host() == Some(host.as_str())
+        {
+            return Some(credentials.clone());
+        }
+        if let Some(credentials) = &self.credentials
+            && url.host() == hops.first.host()
+        {
+            return Some(credentials.clone());
+        }
+        let login = self.netrc.login(url.hostname()?)?;
+        Some(Credentials::password(&login.login, &login.password))
+    }
+}
+
+/// Credentials from the url's `user:password@`, with the host they are for
+pub(super) fn userinfo(url: &URL) -> Option<(String, Credentials)> {
+    let user = url.username()?;
+    let password = url.password().unwrap_or_default();
+    let credentials = Credentials::password(percent_decode(user), percent_decode(password));
+    Some((url.host()?.to_string(), credentials))
+}
+
+/// The strongest challenge the credentials can answer: Digest with SHA-256, then with MD5,
+/// then Basic, or Bearer for a token
+fn authorization(
+    credentials: &Credentials,
+    challenges: &[Challenge],
+    request: &Request,
+) -> Option<String> {
+    let find = |scheme: &'static str| challenges.iter().filter(move |c| c.scheme == scheme);
+    match credentials {
+        Credentials::Bearer(token) => {
+            find("bearer").next()?;
+            Some(format!("Bearer {}", token))
+        }
+        Credentials::Password { user, password } => {
+            let digest = find("digest")
+                .filter_map(|challenge| {
+                    let algorithm = Algorithm::parse(challenge.param("algorithm"))?;
+                    Some((algorithm, challenge))
+                })
+                .max_by_key(|(algorithm, _)| algorithm.hash == Hash::Sha256);
+            if let Some((algorithm, challenge)) = digest
+                && let Some(header) =
+                    digest_authorization(user, password, algorithm, challenge, request, &cnonce())
+            {
+                return Some(header);
+            }
+            find("basic").next()?;
+            let encoded = BASE64_STANDARD.encode(format!("{}:{}", user, password));
+            Some(format!("Basic {}", encoded))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hash {
+    Md5,
+    Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Algorithm {
+    hash: Hash,
+    /// The `-sess` variants hash the nonces into the key
+    session: bool,
+}
+
+impl Algorithm {
+    /// MD5 when the challenge names none
+    fn parse(name: Option<&str>) -> Option<Self> {
+        let name = name.unwrap_or("MD5").to_ascii_uppercase();
+        let (name, session) = match name.strip_suffix("-SESS") {
+            Some(name) => (name, true),
+            None => (name.as_str(), false),
+        };
+        let hash = match name {
+            "MD5" => Hash::Md5,
+            "SHA-256" => Hash::Sha256,
+            _ => return None,
+        };
+        Some(Self { hash, session })
+    }
+
+    fn name(&self) -> String {
+        let name = match self.hash {
+            Hash::Md5 => "MD5",
+            Hash::Sha256 => "SHA-256",
+        };
+        if self.session {
+            format!("{}-sess", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn hex(&self, data: &str) -> String {
+        let bytes: Vec<u8> = match self.hash {
+            Hash::Md5 => Md5::digest(data.as_bytes()).to_vec(),
+            Hash::Sha256 => Sha256::digest(data.as_bytes()).to_vec(),
+        };
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// None when the challenge wants something unsupported, like only `qop=auth-int`
+fn digest_authorization(
+    user: &str,
+    password: &str,
+    algorithm: Algorithm,
+    challenge: &Challenge,
+    request: &Request,
+    cnonce: &str,
+) -> Option<String> {
+    let realm = challenge.param("realm").unwrap_or_default();
+    let nonce = challenge.param("nonce")?;
+    let uri = request.url().path()?;
+    let qop = match challenge.param("qop") {
+        Some(qops) => Some(qops.split(',').map(str::trim).find(|qop| *qop == "auth")?),
+        None => None,
+    };
+    let nc = "00000001";
+
+    let mut ha1 = algorithm.hex(&format!("{}:{}:{}", user, realm, password));
+    if algorithm.session {
+        ha1 = algorithm.hex(&format!("{}:{}:{}", ha1, nonce, cnonce));
+    }
+    let ha2 = algorithm.hex(&format!("{}:{}", request.method(), uri));
+    let response = match qop {
+        Some(qop) => algorithm.hex(&format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1, nonce, nc, cnonce, qop, ha2
+        )),
+        None => algorithm.hex(&format!("{}:{}:{}", ha1, nonce, ha2)),
+    };
+
+    let mut header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+        quote(user),
+        quote(realm),
+        quote(nonce),
+        quote(uri),
+        algorithm.name(),
+        response
+    );
+    if let Some(qop) = qop {
+        header.push_str(&format!(", qop={}, nc={}, cnonce=\"{}\"", qop, nc, cnonce));
+    }
+    if let Some(opaque) = challenge.param("opaque") {
+        header.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+    }
+    Some(header)
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Random enough for a client nonce, from the randomly keyed std hasher
+fn cnonce() -> String {
+    let random = RandomState::new().build_hasher().finish();
+    format!("{:016x}", random)
+}
+
+/// Splits a `WWW-Authenticate` value, which may hold several challenges, each a scheme then
+/// comma separated `name=value` params
+pub(super) fn parse_challenges(header: &str) -> Vec<Challenge> {
+    let mut challenges: Vec<Challenge> = Vec::new();
+    for item in split_outside_quotes(header) {
+        let item = item.trim();
+        if item.is_empty() {
+            continue;
+        }
+        // a new challenge starts with a scheme, a token followed by a space or nothing
+        let scheme_end = item.find([' ', '=']).unwrap_or(item.len());
+        let starts_challenge = item.as_bytes().get(scheme_end) != Some(&b'=');
+        let param = if starts_challenge {
+            challenges.push(Challenge {
+                scheme: item[..scheme_end].to_ascii_lowercase(),
+                params: Vec::new(),
+            });
+            item[scheme_end..].trim()
+        } else {
+            item
+        };
+        let (Some(challenge), Some((name, value))) = (challenges.last_mut(), param.split_once('='))
+        else {
+            continue;
+        };
+        challenge
+            .params
+            .push((name.trim().to_string(), unquote(value.trim())));
+    }
+    challenges
+}
+
+fn split_outside_quotes(header: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in header.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&header[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&header[start..]);
+    items
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    else {
+        return value.to_string();
+    };
+    let mut unquoted = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{engine::test::serve, netrc::Netrc};
+
+    #[test]
+    fn parses_challenges() {
+        let challenges = parse_challenges(
+            r#"Digest realm="a, \"b\"", qop="auth,auth-int", nonce=abc, Basic realm="x", Bearer"#,
+        );
+        assert_eq!(challenges.len(), 3);
+        assert_eq!(challenges[0].scheme, "digest");
+        assert_eq!(challenges[0].param("realm"), Some(r#"a, "b""#));
+        assert_eq!(challenges[0].param("QOP"), Some("auth,auth-int"));
+        assert_eq!(challenges[0].param("nonce"), Some("abc"));
+        assert_eq!(challenges[1].scheme, "basic");
+        assert_eq!(challenges[1].param("realm"), Some("x"));
+        assert_eq!(challenges[2].scheme, "bearer");
+        assert!(challenges[2].params.is_empty());
+    }
+
+    #[test]
+    fn digest_responses() {
+        // the MD5 example from RFC 2617
+        let challenge = &parse_challenges(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )[0];
+        let request = Request::get("http://www.nowhere.org/dir/index.html".parse().unwrap());
+        let header = digest_authorization(
+            "Mufasa",
+            "Circle Of Life",
+            Algorithm::parse(challenge.param("algorithm")).unwrap(),
+            challenge,
+            &request,
+            "0a4f113b",
+        )
+        .unwrap();
+        assert!(header.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+        assert!(header.contains("qop=auth, nc=00000001, cnonce=\"0a4f113b\""));
+        assert!(header.contains(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+
+        // the SHA-256 example from RFC 7616
+        let challenge = &parse_challenges(
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=SHA-256, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+        )[0];
+        let request = Request::get("http://example.org/dir/index.html".parse().unwrap());
+        let header = digest_authorization(
+            "Mufasa",
+            "Circle of Life",
+            Algorithm::parse(challenge.param("algorithm")).unwrap(),
+            challenge,
+            &request,
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        )
+        .unwrap();
+        assert!(header.contains(
+            r#"response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1""#
+        ));
+        assert!(header.contains("algorithm=SHA-256"));
+
+        let auth_int_only = &parse_challenges(r#"Digest nonce="n", qop="auth-int""#)[0];
+        let algorithm = Algorithm::parse(None).unwrap();
+        assert_eq!(
+            digest_authorization("u", "p", algorithm, auth_int_only, &request, "c"),
+            None
+        );
+    }
+
+    #[test]
+    fn retries_once_with_credentials() {
+        let unauthorized = |challenge: &str| {
+            format!(
+                "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: {}\r\nContent-Length: 0\r\n\r\n",
+                challenge
+            )
+        };
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nin".to_string();
+        let (base, server) = serve(vec![
+            unauthorized(r#"Basic realm="intranet""#),
+            ok.clone(),
+            unauthorized(r#"Digest realm="r", nonce="n", qop="auth", algorithm=SHA-256"#),
+            ok.clone(),
+            unauthorized(r#"Bearer realm="api""#),
+            ok,
+            unauthorized(r#"Basic realm="intranet""#),
+            unauthorized(r#"Basic realm="intranet""#),
+            unauthorized(r#"Basic realm="intranet""#),
+        ]);
+        let host = base.trim_start_matches("http://");
+
+        let url: URL = format!("http://alice:pa%24s@{}/", host).parse().unwrap();
+        let response = Engine::new().fetch(&url).unwrap();
+        assert_eq!(response.text(), "in");
+        // the credentials went into the header, not anywhere the url is kept
+        assert_eq!(response.url().to_string(), format!("{}/", base));
+
+        let netrc = Netrc::parse("machine 127.0.0.1 login bob password hunter2");
+        let engine = Engine::new().with_netrc(netrc);
+        let url: URL = format!("{}/digest", base).parse().unwrap();
+        assert_eq!(engine.fetch(&url).unwrap().status(), 200);
+
+        let engine = Engine::new().with_credentials(Credentials::bearer("t0ken"));
+        assert_eq!(engine.fetch(&url).unwrap().status(), 200);
+
+        // wrong credentials get the second 401, without a third try
+        let engine = Engine::new().with_credentials(Credentials::password("eve", "guess"));
+        assert_eq!(engine.fetch(&url).unwrap().status(), 401);
+        // and without any there is nothing to retry with
+        assert_eq!(Engine::new().fetch(&url).unwrap().status(), 401);
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("Authorization"));
+        assert!(requests[1].contains("Authorization: Basic YWxpY2U6cGEkcw==\r\n"));
+        assert!(requests[3].contains("Authorization: Digest username=\"bob\", realm=\"r\""));
+        assert!(requests[3].contains("uri=\"/digest\", algorithm=SHA-256"));
+        assert!(requests[5].contains("Authorization: Bearer t0ken\r\n"));
+        assert!(requests[7].contains("Authorization: Basic ZXZlOmd1ZXNz\r\n"));
+        assert!(!requests[8].contains("Authorization"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Fetching many requests at once on a bounded set of threads
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{Engine, FetchError};
+use crate::{request::Request, response::Response, url::URL};
+
+/// How a batch of fetches is spread out. Limits apply to the host each request starts at,
+/// redirects elsewhere dont count against another host
+#[derive(Debug, Clone)]
+pub struct Batch {
+    concurrency: usize,
+    per_host: usize,
+    delay: Duration,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            per_host: 2,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many fetches run at once overall, 8 by default
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How many fetches run at once against one host, 2 by default
+    pub fn with_per_host_limit(mut self, per_host: usize) -> Self {
+        self.per_host = per_host.max(1);
+        self
+    }
+
+    /// The least time between starting two fetches from the same host, none by default
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// One finished fetch. `index` is the position of its request in the batch
+#[derive(Debug)]
+pub struct BatchResult {
+    pub index: usize,
+    pub url: URL,
+    pub result: Result<Response, FetchError>,
+}
+
+impl Engine {
+    /// Fetches every url, see `send_batch`
+    pub fn fetch_batch(&self, batch: &Batch, urls: &[URL], on_result: impl FnMut(BatchResult)) {
+        let requests = urls.iter().cloned().map(Request::get).collect();
+        self.send_batch(batch, requests, on_result);
+    }
+
+    /// Sends every request, several at a time within the batch's limits, handing each result
+    /// to `on_result` on this thread as soon as it is done. Returns once all are done
+    pub fn send_batch(
+        &self,
+        batch: &Batch,
+        requests: Vec<Request>,
+        mut on_result: impl FnMut(BatchResult),
+    ) {
+        let workers = batch.concurrency.min(requests.len());
+        let queue = Queue::new(batch, requests);
+        let (sender, receiver) = mpsc::channel();
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let sender = sender.clone();
+                let queue = &queue;
+                scope.spawn(move || {
+                    while let Some((index, request, host)) = queue.next() {
+                        let url = request.url().clone();
+                        let result = self.send(request);
+                        queue.done(host.as_deref());
+                        if sender.send(BatchResult { index, url, result }).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+            for result in receiver {
+                on_result(result);
+            }
+        });
+    }
+}
+
+/// The requests not yet started, handed out as their hosts allow
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    per_host: usize,
+    delay: Duration,
+}
+
+struct QueueState {
+    pending: VecDeque<(usize, Request, Option<String>)>,
+    active: HashMap<String, usize>,
+    /// When each host may next have a fetch started
+    next_start: HashMap<String, Instant>,
+}
+
+impl Queue {
+    fn new(batch: &Batch, requests: Vec<Request>) -> Self {
+        let pending = requests
+            .into_iter()
+            .enumerate()
+            .map(|(index, request)| {
+                let host = request.url().hostname().map(str::to_ascii_lowercase);
+                (index, request, host)
+            })
+            .collect();
+        Self {
+            state: Mutex::new(QueueState {
+                pending,
+                active: HashMap::new(),
+                next_start: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+            per_host: batch.per_host,
+            delay: batch.delay,
+        }
+    }
+
+    /// The first request whose host is free, waiting for one if need be. None once all have
+    /// been handed out
+    fn next(&self) -> Option<(usize, Request, Option<String>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.pending.is_empty() {
+                return None;
+            }
+            let now = Instant::now();
+            // the soonest a host held back by the delay frees up
+            let mut wake = None;
+            let ready = state.pending.iter().position(|(_, _, host)| {
+                let Some(host) = host else {
+                    return true;
+                };
+                if state.active.get(host).copied().unwrap_or(0) >= self.per_host {
+                    return false;
+                }
+                match state.next_start.get(host) {
+                    Some(&start) if start > now => {
+                        wake = Some(wake.map_or(start, |wake: Instant| wake.min(start)));
+                        false
+                    }
+                    _ => true,
+                }
+            });
+
+            if let Some(i) = ready {
+                let (index, request, host) = state.pending.remove(i)?;
+                if let Some(host) = &host {
+                    *state.active.entry(host.clone()).or_default() += 1;
+                    state.next_start.insert(host.clone(), now + self.delay);
+                }
+                return Some((index, request, host));
+            }
+            state = match wake {
+                Some(wake) => self.changed.wait_timeout(state, wake - now).unwrap().0,
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn done(&self, host: Option<&str>) {
+        if let Some(host) = host {
+            let mut state = self.state.lock().unwrap();
+            if let Some(active) = state.active.get_mut(host) {
+                *active -= 1;
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use super::*;
+
+    /// Answers every connection after `hold` on its own thread, handing back how many were
+    /// open at once at most and when each arrived
+    fn serve_slowly(
+        count: usize,
+        hold: Duration,
+    ) -> (String, thread::JoinHandle<(usize, Vec<Instant>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let open = Arc::new(AtomicUsize::new(0));
+            let most = Arc::new(AtomicUsize::new(0));
+            let mut arrivals = Vec::new();
+            let mut handlers = Vec::new();
+            for _ in 0..count {
+                let (mut stream, _) = listener.accept().unwrap();
+                arrivals.push(Instant::now());
+                let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now_open, Ordering::SeqCst);
+                let open = open.clone();
+                handlers.push(thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut byte = [0; 1];
+                    while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                        request.push(byte[0]);
+                    }
+                    thread::sleep(hold);
+                    open.fetch_sub(1, Ordering::SeqCst);
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .unwrap();
+                }));
+            }
+            for handler in handlers {
+                handler.join().unwrap();
+            }
+            (most.load(Ordering::SeqCst), arrivals)
+        });
+        (base, handle)
+    }
+
+    #[test]
+    fn limits_fetches_per_host() {
+        let (base, server) = serve_slowly(6, Duration::from_millis(100));
+        let urls: Vec<URL> = (0..6)
+            .map(|i| format!("{}/{}", base, i).parse().unwrap())
+            .collect();
+        let mut indexes = Vec::new();
+        Engine::new().fetch_batch(&Batch::new().with_per_host_limit(2), &urls, |result| {
+            assert_eq!(result.url, urls[result.index]);
+            assert_eq!(result.result.unwrap().text(), "ok");
+            indexes.push(result.index);
+        });
+        indexes.sort();
+        assert_eq!(indexes, [0, 1, 2, 3, 4, 5]);
+
+        let (most, _) = server.join().unwrap();
+        assert_eq!(most, 2);
+    }
+
+    #[test]
+    fn waits_between_fetches_from_a_host() {
+        let (base, server) = serve_slowly(3, Duration::ZERO);
+        let urls: Vec<URL> = (0..3)
+            .map(|i| format!("{}/{}", base, i).parse().unwrap())
+            .collect();
+        let batch = Batch::new()
+            .with_per_host_limit(3)
+            .with_delay(Duration::from_millis(100));
+        let mut count = 0;
+        Engine::new().fetch_batch(&batch, &urls, |_| count += 1);
+        assert_eq!(count, 3);
+
+        let (_, arrivals) = server.join().unwrap();
+        for pair in arrivals.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(90));
+        }
+    }
+}
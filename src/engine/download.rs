@@ -0,0 +1,554 @@
+//! Saving urls to disk, picking up where an earlier, interrupted download stopped
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::{Engine, FetchError, Progress};
+use crate::{
+    request::Request,
+    response::Response,
+    url::{URL, percent_decode},
+};
+
+/// What to download and where to put it
+pub struct Download {
+    url: URL,
+    dir: PathBuf,
+    file: Option<PathBuf>,
+    resume: bool,
+    progress: Option<Box<dyn FnMut(Progress) + Send>>,
+}
+
+impl Download {
+    /// Saves into the current directory, named after the `Content-Disposition` or the url
+    pub fn new(url: URL) -> Self {
+        Self {
+            url,
+            dir: PathBuf::from("."),
+            file: None,
+            resume: true,
+            progress: None,
+        }
+    }
+
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Saves to this file instead of picking a name, relative to the directory
+    pub fn with_file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// Whether to resume from a `.part` file left by an earlier attempt, true by default
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Progress counts the whole file, including what was there before resuming
+    pub fn with_progress(mut self, progress: impl FnMut(Progress) + Send + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Where the body goes until it is complete
+    fn part_path(&self) -> PathBuf {
+        let file = match &self.file {
+            Some(file) => self.dir.join(file),
+            None => self.dir.join(url_filename(&self.url)),
+        };
+        let mut part = file.into_os_string();
+        part.push(".part");
+        PathBuf::from(part)
+    }
+
+    /// Holds the `If-Range` value for the part, without one a part cant be safely resumed
+    fn validator_path(&self) -> PathBuf {
+        let mut validator = self.part_path().into_os_string();
+        validator.push(".validator");
+        PathBuf::from(validator)
+    }
+
+    fn final_path(&self, response: Option<&Response>) -> PathBuf {
+        if let Some(file) = &self.file {
+            return self.dir.join(file);
+        }
+        let disposition = response
+            .and_then(|response| response.header("Content-Disposition"))
+            .and_then(disposition_filename);
+        self.dir
+            .join(disposition.unwrap_or_else(|| url_filename(&self.url)))
+    }
+}
+
+/// A finished download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downloaded {
+    pub path: PathBuf,
+    /// The size of the whole file
+    pub bytes: u64,
+    /// How much was already on disk, 0 unless the server resumed
+    pub resumed_from: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+
+    #[error("couldnt write {path}")]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("server answered {status} {reason}")]
+    Status { status: u32, reason: String },
+
+    #[error("asked to resume at byte {expected}, server sent {content_range:?}")]
+    BadRange {
+        expected: u64,
+        content_range: Option<String>,
+    },
+}
+
+impl Engine {
+    /// Downloads into a `.part` file renamed once complete. When one is left over from before,
+    /// only the rest is asked for, with `If-Range` so a changed file starts over. The validator
+    /// for that is kept next to the part, and a part without one is downloaded again
+    pub fn download(&self, mut download: Download) -> Result<Downloaded, DownloadError> {
+        let part = download.part_path();
+        let validator_path = download.validator_path();
+        let write_error = |source| DownloadError::Write {
+            path: part.clone(),
+            source,
+        };
+        let finish = |path: &Path| -> Result<(), DownloadError> {
+            fs::rename(&part, path).map_err(write_error)?;
+            remove_if_there(&validator_path).map_err(write_error)
+        };
+        let mut restarted = !download.resume;
+        loop {
+            let existing = fs::metadata(&part)
+                .ok()
+                .filter(|meta| meta.len() > 0 && !restarted)
+                .zip(fs::read_to_string(&validator_path).ok());
+            let mut request = Request::get(download.url.clone());
+            if let Some((meta, validator)) = &existing {
+                request = request
+                    .with_header("Range", format!("bytes={}-", meta.len()))
+                    .with_header("If-Range", validator.trim());
+            }
+            let offset = existing.as_ref().map_or(0, |(meta, _)| meta.len());
+
+            let mut streaming = self.send_streaming(request)?;
+            let response = streaming.response().clone();
+            let content_range = response.header("Content-Range").map(str::to_string);
+            let bad_range = || DownloadError::BadRange {
+                expected: offset,
+                content_range: content_range.clone(),
+            };
+            let resumed_from = match response.status() {
+                206 => match content_range.as_deref().and_then(range_start) {
+                    Some(start) if start == offset => offset,
+                    _ => return Err(bad_range()),
+                },
+                416 if existing.is_some() => {
+                    // the part is either the whole file already, or stale and too long
+                    let total = content_range.as_deref().and_then(range_total);
+                    if total == Some(offset) {
+                        let path = download.final_path(None);
+                        finish(&path)?;
+                        return Ok(Downloaded {
+                            path,
+                            bytes: offset,
+                            resumed_from: offset,
+                        });
+                    }
+                    restarted = true;
+                    continue;
+                }
+                _ if response.is_success() => 0,
+                status => {
+                    return Err(DownloadError::Status {
+                        status,
+                        reason: response.reason().to_string(),
+                    });
+                }
+            };
+
+            if let Some(mut progress) = download.progress.take() {
+                streaming = streaming.with_progress(move |body: Progress| {
+                    progress(Progress {
+                        received: resumed_from + body.received,
+                        total: body.total.map(|total| resumed_from + total),
+                    })
+                });
+            }
+            // a server that ignored the range sends everything again, so remember what this
+            // is a copy of before any of it is written
+            if resumed_from == 0 {
+                match validator(&response) {
+                    Some(validator) => fs::write(&validator_path, validator),
+                    None => remove_if_there(&validator_path),
+                }
+                .map_err(write_error)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resumed_from > 0)
+                .truncate(resumed_from == 0)
+                .open(&part)
+                .map_err(write_error)?;
+            let bytes = resumed_from + copy(&mut streaming, &mut file, &part)?;
+
+            let path = download.final_path(Some(&response));
+            finish(&path)?;
+            return Ok(Downloaded {
+                path,
+                bytes,
+                resumed_from,
+            });
+        }
+    }
+}
+
+fn copy(body: &mut impl Read, file: &mut File, path: &Path) -> Result<u64, DownloadError> {
+    let mut buffer = [0; 16 * 1024];
+    let mut copied = 0;
+    loop {
+        let read = body.read(&mut buffer).map_err(FetchError::from)?;
+        if read == 0 {
+            return Ok(copied);
+        }
+        file.write_all(&buffer[..read])
+            .map_err(|source| DownloadError::Write {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        copied += read as u64;
+    }
+}
+
+fn remove_if_there(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// What `If-Range` can compare against, a strong `ETag` or else the `Last-Modified` date
+fn validator(response: &Response) -> Option<&str> {
+    let etag = response
+        .header("ETag")
+        .filter(|etag| !etag.trim_start().starts_with("W/"));
+    let modified = || {
+        response
+            .header("Last-Modified")
+            .filter(|date| httpdate::parse_http_date(date).is_ok())
+    };
+    etag.or_else(modified)
+}
+
+/// The first byte of `bytes start-end/total`
+fn range_start(content_range: &str) -> Option<u64> {
+    let range = content_range.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// The total of `bytes */total`, as a 416 gives it
+fn range_total(content_range: &str) -> Option<u64> {
+    let (_, total) = content_range.trim().split_once('/')?;
+    total.trim().parse().ok()
+}
+
+/// The last segment of the url's path, or index.html for a directory
+fn url_filename(url: &URL) -> String {
+    let path = url.path().unwrap_or("/");
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let segment = path.rsplit('/').next().unwrap_or_default();
+    sanitize(&percent_decode(segment)).unwrap_or_else(|| "index.html".to_string())
+}
+
+/// The filename a `Content-Disposition` suggests, preferring the utf-8 `filename*`
+fn disposition_filename(disposition: &str) -> Option<String> {
+    let params = split_params(disposition);
+    let extended = params.iter().find_map(|(name, value)| {
+        if !name.eq_ignore_ascii_case("filename*") {
+            return None;
+        }
+        // charset'language'percent-encoded, only utf-8 is worth supporting
+        let mut parts = value.splitn(3, '\'');
+        let charset = parts.next()?;
+        let encoded = parts.nth(1)?;
+        charset
+            .eq_ignore_ascii_case("utf-8")
+            .then(|| percent_decode(encoded))
+    });
+    let plain = || {
+        params
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("filename"))
+            .map(|(_, value)| value.clone())
+    };
+    sanitize(&extended.or_else(plain)?)
+}
+
+/// The `name=value` parameters after the disposition type, unquoting values
+fn split_params(header: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = header.chars().peekable();
+    // skip the disposition type
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    loop {
+        let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        if name.trim().is_empty() {
+            return params;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|&c| c != ';').collect();
+        }
+        params.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// Keeps only a plain file name, so a server cant write outside the download directory
+fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::engine::test::serve;
+
+    /// A part left by an earlier attempt, with what to send in `If-Range` if anything
+    fn leftover(dir: &Path, name: &str, data: &[u8], validator: Option<&str>) {
+        fs::write(dir.join(format!("{}.part", name)), data).unwrap();
+        if let Some(validator) = validator {
+            fs::write(dir.join(format!("{}.part.validator", name)), validator).unwrap();
+        }
+    }
+
+    #[test]
+    fn filenames() {
+        let url = |url: &str| url_filename(&url.parse().unwrap());
+        assert_eq!(url("http://example.com/files/a%20b.txt?x=1"), "a b.txt");
+        assert_eq!(url("http://example.com/files/"), "index.html");
+        assert_eq!(url("http://example.com"), "index.html");
+        assert_eq!(url("http://example.com/%2e%2e"), "index.html");
+
+        assert_eq!(
+            disposition_filename(r#"attachment; filename="report \"q1\".pdf""#).as_deref(),
+            Some(r#"report "q1".pdf"#)
+        );
+        assert_eq!(
+            disposition_filename(
+                "attachment; filename=\"plain.txt\"; filename*=UTF-8''%E2%82%AC%20rates.txt"
+            )
+            .as_deref(),
+            Some("€ rates.txt")
+        );
+        assert_eq!(
+            disposition_filename("attachment; filename=../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            disposition_filename(r#"inline; name="a;b"; filename=x.bin"#).as_deref(),
+            Some("x.bin")
+        );
+        assert_eq!(disposition_filename("attachment"), None);
+    }
+
+    #[test]
+    fn names_the_file_from_the_response() {
+        let scratch = tempfile::tempdir().unwrap();
+        let dir = scratch.path();
+        let (base, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=\"report.csv\"\r\n\
+             Content-Length: 5\r\n\r\na,b,c"
+                .to_string(),
+        ]);
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let download = Download::new(format!("{}/export?id=1", base).parse().unwrap())
+            .with_dir(dir)
+            .with_progress({
+                let progress = progress.clone();
+                move |update| progress.lock().unwrap().push(update)
+            });
+        let downloaded = Engine::new().download(download).unwrap();
+        assert_eq!(downloaded.path, dir.join("report.csv"));
+        assert_eq!(downloaded.bytes, 5);
+        assert_eq!(downloaded.resumed_from, 0);
+        assert_eq!(fs::read(dir.join("report.csv")).unwrap(), b"a,b,c");
+        assert!(!dir.join("export.part").exists());
+        assert_eq!(
+            progress.lock().unwrap().last(),
+            Some(&Progress {
+                received: 5,
+                total: Some(5)
+            })
+        );
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("Range"));
+    }
+
+    #[test]
+    fn resumes_a_partial_file() {
+        let scratch = tempfile::tempdir().unwrap();
+        let dir = scratch.path();
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        leftover(dir, "data.bin", b"01234", Some(last_modified));
+        let (base, server) = serve(vec![format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\n\
+             Last-Modified: {}\r\nContent-Length: 5\r\n\r\n56789",
+            last_modified
+        )]);
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let download = Download::new(format!("{}/data.bin", base).parse().unwrap())
+            .with_dir(dir)
+            .with_progress({
+                let progress = progress.clone();
+                move |update| progress.lock().unwrap().push(update)
+            });
+        let downloaded = Engine::new().download(download).unwrap();
+        assert_eq!(downloaded.bytes, 10);
+        assert_eq!(downloaded.resumed_from, 5);
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), b"0123456789");
+        assert!(!dir.join("data.bin.part.validator").exists());
+        assert_eq!(
+            progress.lock().unwrap().last(),
+            Some(&Progress {
+                received: 10,
+                total: Some(10)
+            })
+        );
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=5-\r\n"));
+        assert!(requests[0].contains(&format!("If-Range: {}\r\n", last_modified)));
+    }
+
+    #[test]
+    fn starts_over_when_the_range_is_ignored() {
+        let scratch = tempfile::tempdir().unwrap();
+        let dir = scratch.path();
+        leftover(dir, "out.bin", b"stale", Some("\"v1\""));
+        let (base, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 10\r\n\r\nabcdefghij".to_string(),
+        ]);
+        let download = Download::new(format!("{}/data.bin", base).parse().unwrap())
+            .with_dir(dir)
+            .with_file("out.bin");
+        let downloaded = Engine::new().download(download).unwrap();
+        assert_eq!(downloaded.path, dir.join("out.bin"));
+        assert_eq!(downloaded.resumed_from, 0);
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcdefghij");
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=5-\r\n"));
+        assert!(requests[0].contains("If-Range: \"v1\"\r\n"));
+        assert!(!dir.join("out.bin.part.validator").exists());
+    }
+
+    #[test]
+    fn restarts_without_a_validator() {
+        let scratch = tempfile::tempdir().unwrap();
+        let dir = scratch.path();
+        // as left by a download whose server sent neither a strong ETag nor Last-Modified
+        leftover(dir, "data.bin", b"stale", None);
+        let (base, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: W/\"v1\"\r\nContent-Length: 10\r\n\r\nabcdefghij".to_string(),
+        ]);
+        let download = Download::new(format!("{}/data.bin", base).parse().unwrap()).with_dir(dir);
+        let downloaded = Engine::new().download(download).unwrap();
+        assert_eq!(downloaded.resumed_from, 0);
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), b"abcdefghij");
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("Range"));
+        // the file keeps the time it was written
+        let modified = fs::metadata(dir.join("data.bin"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(modified.elapsed().unwrap() < std::time::Duration::from_secs(60));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        let scratch = tempfile::tempdir().unwrap();
+        let dir = scratch.path();
+        leftover(dir, "done.txt", b"all of it", Some("\"a\""));
+        leftover(dir, "long.txt", b"more than there is", Some("\"b\""));
+        let (base, server) = serve(vec![
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */9\r\n\
+             Content-Length: 0\r\n\r\n"
+                .to_string(),
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */4\r\n\
+             Content-Length: 0\r\n\r\n"
+                .to_string(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nless".to_string(),
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-3/4\r\n\
+             Content-Length: 4\r\n\r\nless"
+                .to_string(),
+        ]);
+        let engine = Engine::new();
+        let download = |name: &str| Download::new(format!("{}/{}", base, name).parse().unwrap());
+
+        let done = engine.download(download("done.txt").with_dir(dir)).unwrap();
+        assert_eq!(done.bytes, 9);
+        assert_eq!(fs::read(dir.join("done.txt")).unwrap(), b"all of it");
+
+        let long = engine.download(download("long.txt").with_dir(dir)).unwrap();
+        assert_eq!(long.resumed_from, 0);
+        assert_eq!(fs::read(dir.join("long.txt")).unwrap(), b"less");
+
+        leftover(dir, "bad.txt", b"abc", Some("\"c\""));
+        let err = engine
+            .download(download("bad.txt").with_dir(dir))
+            .err()
+            .unwrap();
+        assert!(matches!(err, DownloadError::BadRange { expected: 3, .. }));
+        assert_eq!(fs::read(dir.join("bad.txt.part")).unwrap(), b"abc");
+
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("Range"));
+        assert!(!requests[2].contains("Range"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::io;
+
+use thiserror::Error;
+
+use super::TimeoutKind;
+use crate::{parser::HttpResponseParseError, url::ParseError};
+
+/// Everything that can go wrong fetching a url. Underlying errors are kept as the source, so
+/// printing the chain gives the whole story
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("invalid url")]
+    InvalidUrl(#[from] ParseError),
+
+    #[error("cannot request unknown/unsupported scheme in {0}")]
+    UnsupportedScheme(String),
+
+    #[error("couldnt resolve {host}")]
+    Dns {
+        host: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("couldnt connect to {host}:{port}")]
+    Connect {
+        host: String,
+        port: u16,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("proxy {proxy} refused to tunnel, it answered {status}")]
+    ProxyTunnel { proxy: String, status: u32 },
+
+    #[error("socks proxy {proxy} failed: {reason}")]
+    Socks { proxy: String, reason: String },
+
+    #[error("tls error")]
+    Tls(#[from] rustls::Error),
+
+    #[error("{0} timed out")]
+    Timeout(TimeoutKind),
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("too many redirects, gave up after {0}")]
+    TooManyRedirects(u32),
+
+    #[error("refusing to redirect from {from} to insecure {to}")]
+    InsecureRedirect { from: String, to: String },
+
+    #[error("{url} is not in the cache and the cache mode forbids the network")]
+    NotCached { url: String },
+
+    #[error("malformed response")]
+    Protocol(#[from] HttpResponseParseError),
+
+    #[error("couldnt read {path}")]
+    File {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("cache error")]
+    Cache(#[source] anyhow::Error),
+
+    #[error("io error")]
+    Io(#[source] io::Error),
+}
+
+/// Sorts out the io errors that are really tls failures, rustls reports those wrapped in an io
+/// error, and unwraps fetch errors that had to pass through `Read`
+impl From<io::Error> for FetchError {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<FetchError>()) {
+            let inner = err.into_inner().expect("checked above");
+            return *inner.downcast::<FetchError>().expect("checked above");
+        }
+        if err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<rustls::Error>())
+        {
+            let inner = err.into_inner().expect("checked above");
+            return FetchError::Tls(*inner.downcast::<rustls::Error>().expect("checked above"));
+        }
+        FetchError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn sorts_io_errors() {
+        let tls = io::Error::new(io::ErrorKind::InvalidData, rustls::Error::DecryptError);
+        assert!(matches!(
+            FetchError::from(tls),
+            FetchError::Tls(rustls::Error::DecryptError)
+        ));
+
+        let err = FetchError::from(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+        assert!(matches!(err, FetchError::Io(_)));
+        assert_eq!(err.source().unwrap().to_string(), "gone");
+    }
+}
@@ -0,0 +1,432 @@
+//! Sending requests over plain and tls connections and reading responses back
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        Arc,
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rustls::{ClientConfig, ClientConnection, StreamOwned, pki_types::ServerName};
+
+use super::{
+    FetchError,
+    proxy::{Proxy, ProxyKind, tunnel},
+    socks,
+    timeout::{Limits, POLL, TimeoutKind},
+};
+use crate::{
+    parser::HttpResponseParseError,
+    request::{Method, Request},
+    response::{Response, Timings},
+    url::{ParseError, Scheme},
+};
+
+/// A connection to the server, plain or over tls
+pub(super) enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Sends a request and reads the whole response
+pub(super) fn request(
+    request: &Request,
+    cookie: Option<&str>,
+    proxy: Option<&Proxy>,
+    tls: &Arc<ClientConfig>,
+    limits: &Limits,
+) -> Result<Response, FetchError> {
+    let mut timings = Timings::default();
+    let mut connection = open(request, cookie, proxy, tls, limits, &mut timings)?;
+    let message = read_to_end(&mut connection, limits, &mut timings)?;
+    read_response(request, &message, timings)
+}
+
+/// Connects to the request's host, or the proxy, over tls for https, and sends the request
+pub(super) fn open(
+    request: &Request,
+    cookie: Option<&str>,
+    proxy: Option<&Proxy>,
+    tls: &Arc<ClientConfig>,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<Connection, FetchError> {
+    let url = request.url();
+    let host = url.hostname().ok_or(ParseError::HostMissing)?;
+    let https = url.scheme() == Scheme::Https;
+    let port = url.port().unwrap_or(if https { 443 } else { 80 }) as u16;
+    let mut head = request.wire_head(cookie)?;
+
+    let mut socket = match proxy {
+        Some(proxy) => {
+            let mut socket = connect(proxy.host(), proxy.port(), limits, timings)?;
+            match proxy.kind() {
+                ProxyKind::Http if https => tunnel(&mut socket, url, port, proxy, limits)?,
+                ProxyKind::Http => {
+                    head = request.proxy_wire_head(cookie, proxy.authorization().as_deref())?;
+                }
+                ProxyKind::Socks5 | ProxyKind::Socks5h => {
+                    socks::handshake(&mut socket, host, port, proxy, limits, timings)?
+                }
+            }
+            socket
+        }
+        None => connect(host, port, limits, timings)?,
+    };
+
+    let mut connection = if https {
+        let mut client = ClientConnection::new(tls.clone(), server_name(host)?)?;
+        let start = Instant::now();
+        while client.is_handshaking() {
+            limits.retry(TimeoutKind::Read, limits.timeouts.read, || {
+                client.complete_io(&mut socket)
+            })?;
+        }
+        timings.tls = start.elapsed();
+        Connection::Tls(Box::new(StreamOwned::new(client, socket)))
+    } else {
+        Connection::Plain(socket)
+    };
+    write_all(&mut connection, head.as_bytes(), limits)?;
+    write_all(&mut connection, request.body(), limits)?;
+    limits.retry(TimeoutKind::Write, limits.timeouts.write, || {
+        connection.flush()
+    })?;
+    Ok(connection)
+}
+
+pub(super) fn server_name(host: &str) -> Result<ServerName<'static>, ParseError> {
+    ServerName::try_from(host.to_string()).map_err(|_| ParseError::InvalidHost)
+}
+
+/// Resolves and connects on its own thread, so cancelling or a deadline doesnt have to wait for
+/// the os to give up. The socket then blocks for at most `POLL` at a time so later reads and
+/// writes can check too
+fn connect(
+    host: &str,
+    port: u16,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<TcpStream, FetchError> {
+    let (sender, receiver) = mpsc::channel();
+    let timeout = limits.timeouts.connect;
+    let owned_host = host.to_string();
+    thread::spawn(move || {
+        let _ = sender.send(resolve_and_connect(&owned_host, port, timeout));
+    });
+
+    let start = Instant::now();
+    let (stream, dns, connect) = loop {
+        limits.check()?;
+        match receiver.recv_timeout(POLL) {
+            Ok(result) => break result?,
+            Err(RecvTimeoutError::Timeout) => {
+                if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    return Err(FetchError::Timeout(TimeoutKind::Connect));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("connecting thread went away").into());
+            }
+        }
+    };
+    timings.dns = dns;
+    timings.connect = connect;
+    stream.set_read_timeout(Some(POLL))?;
+    stream.set_write_timeout(Some(POLL))?;
+    Ok(stream)
+}
+
+/// Trying each address in turn, returns the stream and how long resolving and connecting took
+fn resolve_and_connect(
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+) -> Result<(TcpStream, Duration, Duration), FetchError> {
+    let start = Instant::now();
+    let addrs: Vec<_> = (host, port)
+        .to_socket_addrs()
+        .map_err(|source| FetchError::Dns {
+            host: host.to_string(),
+            source,
+        })?
+        .collect();
+    let dns = start.elapsed();
+
+    let start = Instant::now();
+    let mut last_err = None;
+    for addr in addrs {
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match stream {
+            Ok(stream) => return Ok((stream, dns, start.elapsed())),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(connect_error(host, port, last_err))
+}
+
+/// Why none of the addresses could be connected to, the last one's error if there were any
+pub(super) fn connect_error(host: &str, port: u16, last_err: Option<io::Error>) -> FetchError {
+    match last_err {
+        Some(source) => FetchError::Connect {
+            host: host.to_string(),
+            port,
+            source,
+        },
+        None => FetchError::Dns {
+            host: host.to_string(),
+            source: io::Error::new(ErrorKind::NotFound, "no addresses"),
+        },
+    }
+}
+
+/// Reads until the server closes the connection
+fn read_to_end(
+    connection: &mut Connection,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, FetchError> {
+    let sent = Instant::now();
+    let mut message = Vec::new();
+    let mut buffer = [0; 8192];
+    let mut first_byte = None;
+    loop {
+        let read = limits.retry(TimeoutKind::Read, limits.timeouts.read, || {
+            match connection.read(&mut buffer) {
+                // plenty of servers close tls connections without a close_notify
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof && !message.is_empty() => Ok(0),
+                result => result,
+            }
+        })?;
+        if read == 0 {
+            break;
+        }
+        first_byte.get_or_insert_with(Instant::now);
+        message.extend_from_slice(&buffer[..read]);
+    }
+    let first_byte = first_byte.unwrap_or_else(Instant::now);
+    timings.ttfb = first_byte - sent;
+    timings.download = first_byte.elapsed();
+    Ok(message)
+}
+
+/// Reads just past the blank line ending the response head, returning the head and whatever
+/// part of the body came with it
+pub(super) fn read_head(
+    connection: &mut impl Read,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<(Vec<u8>, Vec<u8>), FetchError> {
+    let sent = Instant::now();
+    let mut message = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = limits.retry(TimeoutKind::Read, limits.timeouts.read, || {
+            connection.read(&mut buffer)
+        })?;
+        if read == 0 {
+            return Err(HttpResponseParseError::MalformedHeader.into());
+        }
+        if message.is_empty() {
+            timings.ttfb = sent.elapsed();
+        }
+        // the blank line may straddle two reads
+        let searched = message.len().saturating_sub(3);
+        message.extend_from_slice(&buffer[..read]);
+        if let Some(end) = message[searched..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            let rest = message.split_off(searched + end + 4);
+            return Ok((message, rest));
+        }
+    }
+}
+
+pub(super) fn write_all(
+    stream: &mut impl Write,
+    mut data: &[u8],
+    limits: &Limits,
+) -> Result<(), FetchError> {
+    while !data.is_empty() {
+        let written = limits.retry(TimeoutKind::Write, limits.timeouts.write, || {
+            stream.write(data)
+        })?;
+        if written == 0 {
+            return Err(io::Error::from(ErrorKind::WriteZero).into());
+        }
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+/// Parses what the server sent, undoing chunked encoding and dropping anything past
+/// Content-Length
+pub(super) fn read_response(
+    request: &Request,
+    message: &[u8],
+    timings: Timings,
+) -> Result<Response, FetchError> {
+    let mut response = Response::from_http(request.url().clone(), message)?;
+    *response.timings_mut() = timings;
+
+    match framing(request, &response) {
+        Framing::Empty => response.set_body(Vec::new()),
+        Framing::Chunked => {
+            let body = decode_chunked(response.body())?;
+            set_decoded_body(&mut response, body);
+        }
+        Framing::Length(length) => {
+            let mut body = response.body().to_vec();
+            body.truncate(length as usize);
+            response.set_body(body);
+        }
+        Framing::Close => {}
+    }
+    Ok(response)
+}
+
+/// How the end of a response body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Framing {
+    /// There is no body, whatever the headers say
+    Empty,
+    Chunked,
+    Length(u64),
+    /// The body runs until the server closes the connection
+    Close,
+}
+
+pub(super) fn framing(request: &Request, response: &Response) -> Framing {
+    let no_body = request.method() == Method::Head
+        || (100..200).contains(&response.status())
+        || matches!(response.status(), 204 | 304);
+    if no_body {
+        return Framing::Empty;
+    }
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return Framing::Chunked;
+    }
+    match response
+        .header("Content-Length")
+        .and_then(|length| length.trim().parse::<u64>().ok())
+    {
+        Some(length) => Framing::Length(length),
+        None => Framing::Close,
+    }
+}
+
+/// Replaces a chunked body with its decoded bytes, which then have a plain length
+pub(super) fn set_decoded_body(response: &mut Response, body: Vec<u8>) {
+    response.remove_header("Transfer-Encoding");
+    response.set_header("Content-Length", body.len().to_string());
+    response.set_body(body);
+}
+
+pub(crate) fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, HttpResponseParseError> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or(HttpResponseParseError::MalformedChunk)?;
+        let line = String::from_utf8_lossy(&body[..line_end]);
+        // chunk extensions follow a semicolon
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| HttpResponseParseError::MalformedChunk)?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            // trailers, if any, are dropped
+            return Ok(decoded);
+        }
+        let chunk = body
+            .get(..size)
+            .ok_or(HttpResponseParseError::MalformedChunk)?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunked() {
+        let body =
+            b"4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(
+            decode_chunked(body).unwrap(),
+            b"Wikipedia in \r\n\r\nchunks."
+        );
+        assert!(decode_chunked(b"4\r\nWi").is_err());
+        assert!(decode_chunked(b"zz\r\n").is_err());
+    }
+
+    #[test]
+    fn reads_length_and_chunked_bodies() {
+        let request = Request::get("http://example.com/".parse().unwrap());
+        let response = read_response(
+            &request,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokextra",
+            Timings::default(),
+        )
+        .unwrap();
+        assert_eq!(response.body(), b"ok");
+
+        let response = read_response(
+            &request,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n",
+            Timings::default(),
+        )
+        .unwrap();
+        assert_eq!(response.body(), b"ok");
+        assert_eq!(response.header("Transfer-Encoding"), None);
+        assert_eq!(response.header("Content-Length"), Some("2"));
+
+        let head = Request::head("http://example.com/".parse().unwrap());
+        let response = read_response(
+            &head,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n",
+            Timings::default(),
+        )
+        .unwrap();
+        assert!(response.body().is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+//! Opening a tunnel through a SOCKS5 proxy (RFC 1928), with username and password auth
+//! (RFC 1929). `Handshake` builds and checks the messages for both the blocking and async
+//! engines, which only move its bytes
+
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    time::Instant,
+};
+
+use super::{
+    FetchError,
+    http::write_all,
+    proxy::{Proxy, ProxyKind},
+    timeout::{Limits, TimeoutKind},
+};
+use crate::response::Timings;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+
+/// Who the proxy should connect to, a name only when the proxy does the resolving
+pub(super) enum Target<'a> {
+    Ip(IpAddr),
+    Domain(&'a str),
+}
+
+/// Tunnels to `host:port` over a socket connected to the proxy
+pub(super) fn handshake(
+    socket: &mut TcpStream,
+    host: &str,
+    port: u16,
+    proxy: &Proxy,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<(), FetchError> {
+    let target = match proxy.kind() {
+        ProxyKind::Socks5 => {
+            let start = Instant::now();
+            let addr = (host, port)
+                .to_socket_addrs()
+                .map_err(|source| FetchError::Dns {
+                    host: host.to_string(),
+                    source,
+                })?
+                .next()
+                .ok_or_else(|| FetchError::Dns {
+                    host: host.to_string(),
+                    source: io::Error::new(ErrorKind::NotFound, "no addresses"),
+                })?;
+            timings.dns = start.elapsed();
+            Target::Ip(addr.ip())
+        }
+        _ => Target::Domain(host),
+    };
+
+    let mut handshake = Handshake::new(proxy, target, port);
+    let mut step = handshake.start();
+    while let Step::Exchange { send, read } = step {
+        write_all(socket, &send, limits)?;
+        let mut reply = vec![0; read];
+        read_exact(socket, &mut reply, limits)?;
+        step = handshake.advance(&reply)?;
+    }
+    Ok(())
+}
+
+/// What to do next: send some bytes, which may be none, then read exactly so many back
+pub(super) enum Step {
+    Exchange { send: Vec<u8>, read: usize },
+    Done,
+}
+
+enum State {
+    Greeting,
+    Auth,
+    Connect,
+    DomainLength,
+    Bound,
+}
+
+/// The handshake without any io, so the blocking and async engines share one copy of the protocol
+pub(super) struct Handshake<'a> {
+    proxy: &'a Proxy,
+    target: Target<'a>,
+    port: u16,
+    state: State,
+}
+
+impl<'a> Handshake<'a> {
+    pub(super) fn new(proxy: &'a Proxy, target: Target<'a>, port: u16) -> Self {
+        Self {
+            proxy,
+            target,
+            port,
+            state: State::Greeting,
+        }
+    }
+
+    pub(super) fn start(&self) -> Step {
+        Step::Exchange {
+            send: greeting(self.proxy),
+            read: 2,
+        }
+    }
+
+    /// Takes the bytes the last step asked for
+    pub(super) fn advance(&mut self, reply: &[u8]) -> Result<Step, FetchError> {
+        let proxy = self.proxy;
+        let connect = |this: &Self| -> Result<Step, FetchError> {
+            Ok(Step::Exchange {
+                send: connect_request(&this.target, this.port)?,
+                read: 4,
+            })
+        };
+        let (state, step) = match self.state {
+            State::Greeting => match check_method(proxy, pair(reply))? {
+                USER_PASSWORD => (
+                    State::Auth,
+                    Step::Exchange {
+                        send: auth_request(proxy)?,
+                        read: 2,
+                    },
+                ),
+                _ => (State::Connect, connect(self)?),
+            },
+            State::Auth => {
+                check_auth(proxy, pair(reply))?;
+                (State::Connect, connect(self)?)
+            }
+            State::Connect => {
+                let head = reply.try_into().unwrap_or_default();
+                match bound_address_length(proxy, head)? {
+                    Some(length) => (State::Bound, read_only(length + 2)),
+                    None => (State::DomainLength, read_only(1)),
+                }
+            }
+            State::DomainLength => (State::Bound, read_only(reply[0] as usize + 2)),
+            // the address and port the proxy connected from, which arent needed
+            State::Bound => (State::Bound, Step::Done),
+        };
+        self.state = state;
+        Ok(step)
+    }
+}
+
+fn pair(reply: &[u8]) -> [u8; 2] {
+    reply.try_into().unwrap_or_default()
+}
+
+fn read_only(read: usize) -> Step {
+    Step::Exchange {
+        send: Vec::new(),
+        read,
+    }
+}
+
+fn read_exact(
+    socket: &mut TcpStream,
+    mut buf: &mut [u8],
+    limits: &Limits,
+) -> Result<(), FetchError> {
+    while !buf.is_empty() {
+        let read = limits.retry(TimeoutKind::Read, limits.timeouts.read, || socket.read(buf))?;
+        if read == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "socks proxy hung up").into());
+        }
+        buf = &mut buf[read..];
+    }
+    Ok(())
+}
+
+fn error(proxy: &Proxy, reason: impl Into<String>) -> FetchError {
+    FetchError::Socks {
+        proxy: proxy.address(),
+        reason: reason.into(),
+    }
+}
+
+/// The auth methods on offer, username and password only when there are credentials
+fn greeting(proxy: &Proxy) -> Vec<u8> {
+    match proxy.credentials() {
+        Some(_) => vec![VERSION, 2, NO_AUTH, USER_PASSWORD],
+        None => vec![VERSION, 1, NO_AUTH],
+    }
+}
+
+/// The method the proxy picked
+fn check_method(proxy: &Proxy, reply: [u8; 2]) -> Result<u8, FetchError> {
+    match reply {
+        [VERSION, NO_AUTH] => Ok(NO_AUTH),
+        [VERSION, USER_PASSWORD] if proxy.credentials().is_some() => Ok(USER_PASSWORD),
+        [VERSION, NO_ACCEPTABLE_METHOD] => Err(error(proxy, "no acceptable auth method")),
+        [VERSION, _] => Err(error(proxy, "picked an auth method that wasnt offered")),
+        _ => Err(error(proxy, "not a socks5 proxy")),
+    }
+}
+
+fn auth_request(proxy: &Proxy) -> Result<Vec<u8>, FetchError> {
+    let (user, password) = proxy.credentials().unwrap_or_default();
+    let (Ok(user_length), Ok(password_length)) =
+        (u8::try_from(user.len()), u8::try_from(password.len()))
+    else {
+        return Err(error(proxy, "username or password longer than 255 bytes"));
+    };
+    let mut request = vec![1, user_length];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password_length);
+    request.extend_from_slice(password.as_bytes());
+    Ok(request)
+}
+
+fn check_auth(proxy: &Proxy, reply: [u8; 2]) -> Result<(), FetchError> {
+    match reply {
+        [1, 0] => Ok(()),
+        _ => Err(error(proxy, "authentication failed")),
+    }
+}
+
+fn connect_request(target: &Target, port: u16) -> Result<Vec<u8>, FetchError> {
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target {
+        Target::Ip(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Target::Ip(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Target::Domain(domain) => {
+            let length = u8::try_from(domain.len()).map_err(|_| FetchError::Dns {
+                host: domain.to_string(),
+                source: io::Error::new(ErrorKind::InvalidInput, "name too long for socks"),
+            })?;
+            request.extend([3, length]);
+            request.extend_from_slice(domain.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+/// Checks the first four bytes of the reply, returning the length of the address that follows.
+/// None for a domain, whose length is the next byte
+fn bound_address_length(proxy: &Proxy, head: [u8; 4]) -> Result<Option<usize>, FetchError> {
+    let [version, reply, _, address_type] = head;
+    if version != VERSION {
+        return Err(error(proxy, "not a socks5 proxy"));
+    }
+    let reason = match reply {
+        0 => None,
+        1 => Some("general failure"),
+        2 => Some("connection not allowed by ruleset"),
+        3 => Some("network unreachable"),
+        4 => Some("host unreachable"),
+        5 => Some("connection refused"),
+        6 => Some("ttl expired"),
+        7 => Some("command not supported"),
+        8 => Some("address type not supported"),
+        _ => Some("unknown error"),
+    };
+    if let Some(reason) = reason {
+        return Err(error(proxy, reason));
+    }
+    match address_type {
+        1 => Ok(Some(4)),
+        3 => Ok(None),
+        4 => Ok(Some(16)),
+        _ => Err(error(proxy, "unknown address type in reply")),
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::{
+        io::Write,
+        net::{Ipv4Addr, TcpListener},
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::engine::Engine;
+
+    /// What the stand-in saw: who it was asked to connect to and the request that followed
+    #[derive(Debug)]
+    pub(in crate::engine) struct Seen {
+        pub(in crate::engine) target: String,
+        pub(in crate::engine) request: String,
+    }
+
+    /// A SOCKS5 proxy that answers the tunnelled request itself instead of connecting anywhere.
+    /// Given credentials it insists on them
+    pub(in crate::engine) fn stand_in(
+        credentials: Option<(&'static str, &'static str)>,
+        response: &'static str,
+    ) -> (u16, JoinHandle<Result<Seen, String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let read = |stream: &mut TcpStream, length: usize| {
+                let mut bytes = vec![0; length];
+                stream.read_exact(&mut bytes).unwrap();
+                bytes
+            };
+            let greeting = read(&mut stream, 2);
+            assert_eq!(greeting[0], VERSION);
+            let methods = read(&mut stream, greeting[1] as usize);
+            if let Some((user, password)) = credentials {
+                if !methods.contains(&USER_PASSWORD) {
+                    stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).unwrap();
+                    return Err("no credentials offered".to_string());
+                }
+                stream.write_all(&[VERSION, USER_PASSWORD]).unwrap();
+                let length = read(&mut stream, 2)[1];
+                let sent_user = read(&mut stream, length as usize);
+                let length = read(&mut stream, 1)[0];
+                let sent_password = read(&mut stream, length as usize);
+                if sent_user != user.as_bytes() || sent_password != password.as_bytes() {
+                    stream.write_all(&[1, 1]).unwrap();
+                    return Err("wrong credentials".to_string());
+                }
+                stream.write_all(&[1, 0]).unwrap();
+            } else {
+                stream.write_all(&[VERSION, NO_AUTH]).unwrap();
+            }
+
+            let head = read(&mut stream, 4);
+            assert_eq!(head[..3], [VERSION, CONNECT, 0]);
+            let host = match head[3] {
+                1 => Ipv4Addr::from(<[u8; 4]>::try_from(read(&mut stream, 4)).unwrap()).to_string(),
+                3 => {
+                    let length = read(&mut stream, 1)[0] as usize;
+                    String::from_utf8(read(&mut stream, length)).unwrap()
+                }
+                4 => {
+                    let ip = <[u8; 16]>::try_from(read(&mut stream, 16)).unwrap();
+                    std::net::Ipv6Addr::from(ip).to_string()
+                }
+                other => panic!("address type {}", other),
+            };
+            let port = u16::from_be_bytes(<[u8; 2]>::try_from(read(&mut stream, 2)).unwrap());
+            stream
+                .write_all(&[VERSION, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .unwrap();
+
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.extend(read(&mut stream, 1));
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            Ok(Seen {
+                target: format!("{}:{}", host, port),
+                request: String::from_utf8(request).unwrap(),
+            })
+        });
+        (port, handle)
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsocked";
+
+    #[test]
+    fn tunnels_with_remote_dns_and_auth() {
+        let (port, proxy) = stand_in(Some(("alice", "secret")), OK);
+        let engine = Engine::new()
+            .with_proxy(Proxy::socks5h("127.0.0.1", port).with_credentials("alice", "secret"));
+        let response = engine
+            .fetch(&"http://unresolvable.invalid/page".parse().unwrap())
+            .unwrap();
+        assert_eq!(response.text(), "socked");
+        let seen = proxy.join().unwrap().unwrap();
+        assert_eq!(seen.target, "unresolvable.invalid:80");
+        // unlike an http proxy, the server gets an ordinary request
+        assert!(seen.request.starts_with("GET /page HTTP/1.1\r\n"));
+        assert!(!seen.request.contains("Proxy-Authorization"));
+    }
+
+    #[test]
+    fn tunnels_with_local_dns() {
+        let (port, proxy) = stand_in(None, OK);
+        let engine = Engine::new().with_proxy(Proxy::socks5("127.0.0.1", port));
+        let response = engine
+            .fetch(&"http://localhost:8080/".parse().unwrap())
+            .unwrap();
+        assert_eq!(response.text(), "socked");
+        let seen = proxy.join().unwrap().unwrap();
+        assert!(
+            ["127.0.0.1:8080", "::1:8080"].contains(&seen.target.as_str()),
+            "{}",
+            seen.target
+        );
+    }
+
+    #[test]
+    fn refuses_bad_credentials() {
+        let url = "http://example.com/".parse().unwrap();
+        let (port, proxy) = stand_in(Some(("alice", "secret")), OK);
+        let err = Engine::new()
+            .with_proxy(Proxy::socks5h("127.0.0.1", port).with_credentials("alice", "guess"))
+            .fetch(&url)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "socks proxy 127.0.0.1:{} failed: authentication failed",
+                port
+            )
+        );
+        assert!(proxy.join().unwrap().is_err());
+
+        let (port, proxy) = stand_in(Some(("alice", "secret")), OK);
+        let err = Engine::new()
+            .with_proxy(Proxy::socks5h("127.0.0.1", port))
+            .fetch(&url)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, FetchError::Socks { reason, .. } if reason == "no acceptable auth method")
+        );
+        assert!(proxy.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn reads_replies() {
+        let proxy = Proxy::socks5("p", 1);
+        assert_eq!(bound_address_length(&proxy, [5, 0, 0, 1]).unwrap(), Some(4));
+        assert_eq!(bound_address_length(&proxy, [5, 0, 0, 3]).unwrap(), None);
+        let err = bound_address_length(&proxy, [5, 5, 0, 1]).err().unwrap();
+        assert!(matches!(err, FetchError::Socks { reason, .. } if reason == "connection refused"));
+        assert_eq!(
+            connect_request(&Target::Domain("a.b"), 443).unwrap(),
+            [5, 1, 0, 3, 3, b'a', b'.', b'b', 1, 187]
+        );
+    }
+}
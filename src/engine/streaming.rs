@@ -0,0 +1,376 @@
+//! Reading response bodies as they arrive instead of all at once
+
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Read};
+
+use super::{
+    Engine, FetchError, Hops,
+    caching::{Plan, Update, plan},
+    http::{self, Connection, Framing, framing, set_decoded_body},
+    timeout::{Limits, TimeoutKind},
+};
+use crate::{
+    request::Request,
+    response::{Response, Timings},
+    url::{Scheme, URL},
+};
+
+/// How much of a body has arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub received: u64,
+    /// The Content-Length, when the server sent one
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Between 0 and 1, when the total is known
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.received as f64 / total as f64),
+            None => None,
+        }
+    }
+}
+
+/// A response whose body is read from it rather than held in memory
+pub struct StreamingResponse {
+    response: Response,
+    body: Body,
+}
+
+impl StreamingResponse {
+    /// The status, headers, final url and redirects. Its own body stays empty
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    /// Calls `progress` after every read that gets part of the body
+    pub fn with_progress(mut self, progress: impl FnMut(Progress) + Send + 'static) -> Self {
+        self.body.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            received: self.body.received,
+            total: self.body.total,
+        }
+    }
+
+    /// Reads the rest of the body, for a whole `Response` as `Engine::send` gives
+    pub fn into_response(mut self) -> Result<Response, FetchError> {
+        let mut body = Vec::new();
+        self.body.read_to_end(&mut body)?;
+        if self.body.framing == Framing::Chunked {
+            set_decoded_body(&mut self.response, body);
+        } else {
+            self.response.set_body(body);
+        }
+        Ok(self.response)
+    }
+}
+
+impl Read for StreamingResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+/// Undoes the framing of a body, chunked or not, as it is read
+struct Body {
+    reader: BufReader<Box<dyn Read + Send>>,
+    framing: Framing,
+    /// Left of the whole body for `Length`, or of the current chunk for `Chunked`
+    remaining: u64,
+    done: bool,
+    received: u64,
+    total: Option<u64>,
+    progress: Option<Box<dyn FnMut(Progress) + Send>>,
+}
+
+impl Body {
+    fn new(reader: impl Read + Send + 'static, framing: Framing) -> Self {
+        let (remaining, total) = match framing {
+            Framing::Empty => (0, Some(0)),
+            Framing::Length(length) => (length, Some(length)),
+            Framing::Chunked | Framing::Close => (0, None),
+        };
+        Self {
+            reader: BufReader::new(Box::new(reader)),
+            framing,
+            remaining,
+            done: framing == Framing::Empty,
+            received: 0,
+            total,
+            progress: None,
+        }
+    }
+
+    /// A body already in memory, like a cached one
+    fn buffered(body: Vec<u8>) -> Self {
+        let length = body.len() as u64;
+        Self::new(Cursor::new(body), Framing::Length(length))
+    }
+
+    /// Reads at most `remaining` bytes, which have to be there
+    fn read_remaining(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "body ended early"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "body ended early"));
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+    }
+
+    fn read_chunked(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            // chunk extensions follow a semicolon
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "bad chunk size"))?;
+            if self.remaining == 0 {
+                // trailers, if any, are dropped
+                while !self.read_line()?.is_empty() {}
+                return Ok(0);
+            }
+        }
+        let read = self.read_remaining(buf)?;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "chunk too long"));
+        }
+        Ok(read)
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let read = match self.framing {
+            Framing::Empty => 0,
+            Framing::Length(_) if self.remaining == 0 => 0,
+            Framing::Length(_) => self.read_remaining(buf)?,
+            Framing::Chunked => self.read_chunked(buf)?,
+            Framing::Close => self.reader.read(buf)?,
+        };
+        if read == 0 {
+            self.done = true;
+            return Ok(0);
+        }
+        self.received += read as u64;
+        let progress = Progress {
+            received: self.received,
+            total: self.total,
+        };
+        if let Some(callback) = &mut self.progress {
+            callback(progress);
+        }
+        Ok(read)
+    }
+}
+
+/// The connection as a plain reader, keeping to the fetch's timeouts and cancel handle
+struct Limited {
+    connection: Connection,
+    limits: Limits,
+}
+
+impl Read for Limited {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self
+            .limits
+            .retry(TimeoutKind::Read, self.limits.timeouts.read, || {
+                match self.connection.read(buf) {
+                    // plenty of servers close tls connections without a close_notify, the
+                    // framing tells whether the body was cut short
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(0),
+                    result => result,
+                }
+            });
+        read.map_err(|err| match err {
+            FetchError::Timeout(_) => io::Error::new(ErrorKind::TimedOut, err),
+            err => io::Error::other(err),
+        })
+    }
+}
+
+impl Engine {
+    pub fn fetch_streaming(&self, url: &URL) -> Result<StreamingResponse, FetchError> {
+        self.send_streaming(Request::get(url.clone()))
+    }
+
+    /// `send`, except it returns as soon as the final response head is in and the body is
+    /// read from the result. Streamed bodies arent stored in the cache, and the timings stop
+    /// at the head. Read errors wrap a `FetchError`, which `?` on a `FetchError` result unwraps
+    pub fn send_streaming(&self, mut request: Request) -> Result<StreamingResponse, FetchError> {
+        let limits = Limits::new(self.timeouts, self.cancel.clone());
+        let mut hops = Hops::new(&mut request);
+        loop {
+            limits.check()?;
+            // a redirect's body is dropped unread, closing its connection
+            let (mut response, mut body) =
+                self.request_streaming(&request, hops.same_site, &limits)?;
+            if let Some(retry) = self.authorize(&hops, &request, &response) {
+                request = retry;
+                (response, body) = self.request_streaming(&request, hops.same_site, &limits)?;
+            }
+            match self.next_hop(&mut hops, &request, &mut response)? {
+                Some(next) => request = next,
+                None => return Ok(StreamingResponse { response, body }),
+            }
+        }
+    }
+
+    fn request_streaming(
+        &self,
+        request: &Request,
+        same_site: bool,
+        limits: &Limits,
+    ) -> Result<(Response, Body), FetchError> {
+        let url = request.url();
+        let update = match url.scheme() {
+            Scheme::Http | Scheme::Https => {
+                match plan(self.cache.as_ref(), self.cache_mode, request)? {
+                    Plan::Cached(mut response) => {
+                        let body = Body::buffered(response.take_body());
+                        return Ok((*response, body));
+                    }
+                    Plan::Network(update) => update,
+                }
+            }
+            _ => {
+                let mut response = self.request(request, same_site, limits)?;
+                let body = Body::buffered(response.take_body());
+                return Ok((response, body));
+            }
+        };
+
+        let cookie = self.cookies.cookie_header(url, same_site);
+        let mut timings = Timings::default();
+        let proxy = self.proxies.for_url(url);
+        let mut connection = http::open(
+            request,
+            cookie.as_deref(),
+            proxy,
+            &self.tls,
+            limits,
+            &mut timings,
+        )?;
+        let (head, rest) = http::read_head(&mut connection, limits, &mut timings)?;
+        let mut response = Response::from_http(url.clone(), &head)?;
+        *response.timings_mut() = timings;
+        self.cookies
+            .store_response_cookies(url, response.header_values("Set-Cookie"));
+        if let Some(update @ Update::Invalidate(_)) = update {
+            update.apply(request, &response)?;
+        }
+
+        let framing = framing(request, &response);
+        let connection = Limited {
+            connection,
+            limits: limits.clone(),
+        };
+        Ok((
+            response,
+            Body::new(Cursor::new(rest).chain(connection), framing),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::engine::test::serve;
+
+    fn read_in_threes(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut buffer = [0; 3];
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => return Ok(body),
+                read => body.extend_from_slice(&buffer[..read]),
+            }
+        }
+    }
+
+    #[test]
+    fn undoes_framing() {
+        let chunked = b"4\r\nWiki\r\n6;ext=1\r\npedia \r\n0\r\nTrailer: x\r\n\r\nnext response";
+        let body = Body::new(Cursor::new(chunked.to_vec()), Framing::Chunked);
+        assert_eq!(read_in_threes(body).unwrap(), b"Wikipedia ");
+
+        let body = Body::new(Cursor::new(b"hello, world".to_vec()), Framing::Length(5));
+        assert_eq!(read_in_threes(body).unwrap(), b"hello");
+        let body = Body::new(Cursor::new(b"hel".to_vec()), Framing::Length(5));
+        let err = read_in_threes(body).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let body = Body::new(Cursor::new(b"all of it".to_vec()), Framing::Close);
+        assert_eq!(read_in_threes(body).unwrap(), b"all of it");
+        let body = Body::new(Cursor::new(b"ignored".to_vec()), Framing::Empty);
+        assert_eq!(read_in_threes(body).unwrap(), b"");
+    }
+
+    #[test]
+    fn streams_with_progress() {
+        let (base, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /file\r\nContent-Length: 5\r\n\r\nmoved".to_string(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789".to_string(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n".to_string(),
+        ]);
+        let engine = Engine::new();
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let streaming = engine
+            .fetch_streaming(&format!("{}/", base).parse().unwrap())
+            .unwrap()
+            .with_progress({
+                let updates = updates.clone();
+                move |progress| updates.lock().unwrap().push(progress)
+            });
+        assert_eq!(streaming.response().status(), 200);
+        assert_eq!(
+            streaming.response().url().to_string(),
+            format!("{}/file", base)
+        );
+        assert_eq!(streaming.response().redirects().len(), 1);
+        assert!(streaming.response().body().is_empty());
+
+        assert_eq!(read_in_threes(streaming).unwrap(), b"0123456789");
+        let updates = updates.lock().unwrap();
+        assert_eq!(updates.len(), 4);
+        assert_eq!(
+            updates.last(),
+            Some(&Progress {
+                received: 10,
+                total: Some(10)
+            })
+        );
+        assert_eq!(updates[0].fraction(), Some(0.3));
+
+        let response = engine
+            .fetch_streaming(&format!("{}/chunked", base).parse().unwrap())
+            .unwrap()
+            .into_response()
+            .unwrap();
+        assert_eq!(response.text(), "ok");
+        assert_eq!(response.header("Transfer-Encoding"), None);
+        assert_eq!(response.header("Content-Length"), Some("2"));
+        server.join().unwrap();
+    }
+}
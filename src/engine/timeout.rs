@@ -0,0 +1,137 @@
+//! Deadlines and cancellation for fetches
+
+use std::{
+    fmt::Display,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use super::FetchError;
+
+/// How long sockets block before we check for cancellation and deadlines again
+pub(super) const POLL: Duration = Duration::from_millis(50);
+
+/// Limits on each phase of a fetch, none by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Resolving the host and connecting to it
+    pub connect: Option<Duration>,
+    /// Waiting for the next bytes from the server, the tls handshake included
+    pub read: Option<Duration>,
+    /// Waiting for the server to take more of the request
+    pub write: Option<Duration>,
+    /// The whole fetch, including any redirects
+    pub total: Option<Duration>,
+}
+
+/// Which limit ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    Read,
+    Write,
+    Total,
+}
+
+impl Display for TimeoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phase = match self {
+            TimeoutKind::Connect => "connect",
+            TimeoutKind::Read => "read",
+            TimeoutKind::Write => "write",
+            TimeoutKind::Total => "fetch",
+        };
+        write!(f, "{}", phase)
+    }
+}
+
+/// Aborts fetches from another thread. Clones share the same flag, and once cancelled every
+/// fetch made with the handle fails with `FetchError::Cancelled`, in flight or not yet started,
+/// until `reset` lets them through again. The flag belongs to the engine rather than to each
+/// request, so a reset while a fetch is being cancelled may let that fetch carry on
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Clears the flag for fetches started afterwards
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The timeouts and cancellation for one call to `Engine::send`
+#[derive(Debug, Clone)]
+pub(super) struct Limits {
+    pub(super) timeouts: Timeouts,
+    deadline: Option<Instant>,
+    cancel: CancelHandle,
+}
+
+impl Limits {
+    pub(super) fn new(timeouts: Timeouts, cancel: CancelHandle) -> Self {
+        Self {
+            timeouts,
+            deadline: timeouts.total.map(|total| Instant::now() + total),
+            cancel,
+        }
+    }
+
+    /// Fails once cancelled or past the overall deadline
+    pub(super) fn check(&self) -> Result<(), FetchError> {
+        if self.cancel.is_cancelled() {
+            return Err(FetchError::Cancelled);
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(FetchError::Timeout(TimeoutKind::Total));
+        }
+        Ok(())
+    }
+
+    /// Runs a socket operation, retrying while it times out after `POLL`, until it succeeds,
+    /// fails for real, or `timeout` passes without it getting anywhere
+    pub(super) fn retry<T>(
+        &self,
+        kind: TimeoutKind,
+        timeout: Option<Duration>,
+        mut op: impl FnMut() -> io::Result<T>,
+    ) -> Result<T, FetchError> {
+        let start = Instant::now();
+        loop {
+            self.check()?;
+            match op() {
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                        return Err(FetchError::Timeout(kind));
+                    }
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! How https servers are verified and what certificate, if any, the client presents
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("couldnt read {path}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("no certificates in the PEM")]
+    NoCertificates,
+
+    #[error("invalid PEM")]
+    Pem(#[source] rustls::pki_types::pem::Error),
+
+    #[error("couldnt load the system trust store")]
+    SystemRoots(#[source] rustls_native_certs::Error),
+
+    #[error("tls error")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Builds the rustls config the engine shares between all its https connections. By default
+/// servers are verified against the webpki roots, Mozilla's trust store
+#[derive(Debug)]
+pub struct TlsConfig {
+    webpki_roots: bool,
+    system_roots: bool,
+    extra_roots: Vec<CertificateDer<'static>>,
+    insecure: bool,
+    client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            webpki_roots: true,
+            system_roots: false,
+            extra_roots: Vec::new(),
+            insecure: false,
+            client_cert: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_webpki_roots(mut self, trust: bool) -> Self {
+        self.webpki_roots = trust;
+        self
+    }
+
+    /// Also trusts what the operating system trusts, loaded when the config is built
+    pub fn with_system_roots(mut self, trust: bool) -> Self {
+        self.system_roots = trust;
+        self
+    }
+
+    /// Also trusts every certificate in a PEM bundle, like a private CA's
+    pub fn with_pem_roots(mut self, pem: &[u8]) -> Result<Self, TlsError> {
+        self.extra_roots.extend(certificates(pem)?);
+        Ok(self)
+    }
+
+    pub fn with_pem_roots_file(self, path: &Path) -> Result<Self, TlsError> {
+        let pem = read(path)?;
+        self.with_pem_roots(&pem)
+    }
+
+    /// Accepts any certificate for any name, which anyone in the middle can then read and
+    /// change. Only for testing against servers with self-signed certificates
+    pub fn with_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Presents a certificate chain to servers that ask for one, for mutual tls. Both are PEM,
+    /// the chain starting with the client's own certificate
+    pub fn with_client_cert(mut self, chain: &[u8], key: &[u8]) -> Result<Self, TlsError> {
+        let chain = certificates(chain)?;
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(TlsError::Pem)?;
+        self.client_cert = Some((chain, key));
+        Ok(self)
+    }
+
+    pub fn with_client_cert_files(self, chain: &Path, key: &Path) -> Result<Self, TlsError> {
+        let (chain, key) = (read(chain)?, read(key)?);
+        self.with_client_cert(&chain, &key)
+    }
+
+    /// For `Engine::with_tls_config`, build once and share
+    pub fn build(&self) -> Result<Arc<ClientConfig>, TlsError> {
+        let mut roots = RootCertStore::empty();
+        if self.webpki_roots {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        if self.system_roots {
+            let mut loaded = rustls_native_certs::load_native_certs();
+            // some unreadable stores are fine as long as something was found
+            if loaded.certs.is_empty() && !loaded.errors.is_empty() {
+                return Err(TlsError::SystemRoots(loaded.errors.remove(0)));
+            }
+            roots.add_parsable_certificates(loaded.certs);
+        }
+        for root in &self.extra_roots {
+            roots.add(root.clone())?;
+        }
+
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let mut config = match &self.client_cert {
+            Some((chain, key)) => builder.with_client_auth_cert(chain.clone(), key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+        if self.insecure {
+            let provider = config.crypto_provider().clone();
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AcceptAnything(provider)));
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+/// The default config, built the first time it is needed
+pub(super) fn default_config() -> Arc<ClientConfig> {
+    static DEFAULT: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    DEFAULT
+        .get_or_init(|| {
+            TlsConfig::new()
+                .build()
+                .expect("the webpki roots are valid")
+        })
+        .clone()
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(TlsError::Pem)?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    Ok(certificates)
+}
+
+/// Skips checking the certificate, though the handshake signatures are still checked so the
+/// connection works at all
+#[derive(Debug)]
+struct AcceptAnything(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnything {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::{ServerConfig, ServerConnection, StreamOwned, server::WebPkiClientVerifier};
+
+    use super::*;
+    use crate::engine::{Engine, FetchError};
+
+    struct Ca {
+        issuer: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let issuer =
+                CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            Self { issuer }
+        }
+
+        /// A certificate for the name, its chain and key as PEM
+        fn issue(&self, name: &str) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &self.issuer)
+                .unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn pem(&self) -> String {
+            self.issuer.pem()
+        }
+    }
+
+    fn server_config(chain: &str, key: &str, client_ca: Option<&Ca>) -> ServerConfig {
+        let builder = ServerConfig::builder();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(ca.issuer.der().clone()).unwrap();
+                builder.with_client_cert_verifier(
+                    WebPkiClientVerifier::builder(Arc::new(roots))
+                        .build()
+                        .unwrap(),
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+        builder
+            .with_single_cert(
+                certificates(chain.as_bytes()).unwrap(),
+                PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+            )
+            .unwrap()
+    }
+
+    /// Answers `connections` https connections with a small page, returning whether each
+    /// handshake worked
+    fn serve_tls(config: ServerConfig, connections: usize) -> (String, JoinHandle<Vec<bool>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!(
+            "https://localhost:{}",
+            listener.local_addr().unwrap().port()
+        );
+        let config = Arc::new(config);
+        let handle = thread::spawn(move || {
+            (0..connections)
+                .map(|_| {
+                    let (socket, _) = listener.accept().unwrap();
+                    let connection = ServerConnection::new(config.clone()).unwrap();
+                    let mut stream = StreamOwned::new(connection, socket);
+                    let mut request = Vec::new();
+                    let mut byte = [0];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        if stream.read(&mut byte).unwrap_or(0) == 0 {
+                            return false;
+                        }
+                        request.push(byte[0]);
+                    }
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecret";
+                    stream.write_all(response).unwrap();
+                    stream.conn.send_close_notify();
+                    stream.flush().unwrap();
+                    true
+                })
+                .collect()
+        });
+        (base, handle)
+    }
+
+    #[test]
+    fn trusts_extra_roots() {
+        let ca = Ca::new();
+        let (chain, key) = ca.issue("localhost");
+        let (base, server) = serve_tls(server_config(&chain, &key, None), 2);
+        let url = format!("{}/", base).parse().unwrap();
+
+        let err = Engine::new().fetch(&url).err().unwrap();
+        assert!(matches!(
+            err,
+            FetchError::Tls(rustls::Error::InvalidCertificate(_))
+        ));
+
+        let config = TlsConfig::new()
+            .with_pem_roots(ca.pem().as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let response = Engine::new().with_tls_config(config).fetch(&url).unwrap();
+        assert_eq!(response.text(), "secret");
+        assert_eq!(server.join().unwrap(), [false, true]);
+
+        assert!(matches!(
+            TlsConfig::new().with_pem_roots(b"not a certificate"),
+            Err(TlsError::NoCertificates)
+        ));
+    }
+
+    #[test]
+    fn insecure_accepts_any_certificate() {
+        // signed by a ca nobody trusts, for some other name
+        let (chain, key) = Ca::new().issue("example.com");
+        let (base, server) = serve_tls(server_config(&chain, &key, None), 1);
+        let config = TlsConfig::new().with_insecure(true).build().unwrap();
+        let response = Engine::new()
+            .with_tls_config(config)
+            .fetch(&format!("{}/", base).parse().unwrap())
+            .unwrap();
+        assert_eq!(response.text(), "secret");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn presents_client_certificates() {
+        let ca = Ca::new();
+        let (chain, key) = ca.issue("localhost");
+        let (base, server) = serve_tls(server_config(&chain, &key, Some(&ca)), 2);
+        let url = format!("{}/", base).parse().unwrap();
+        let trusting = || {
+            TlsConfig::new()
+                .with_pem_roots(ca.pem().as_bytes())
+                .unwrap()
+        };
+
+        let without = Engine::new().with_tls_config(trusting().build().unwrap());
+        assert!(without.fetch(&url).is_err());
+
+        let (client_chain, client_key) = ca.issue("client");
+        let config = trusting()
+            .with_client_cert(client_chain.as_bytes(), client_key.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let response = Engine::new().with_tls_config(config).fetch(&url).unwrap();
+        assert_eq!(response.text(), "secret");
+        assert_eq!(server.join().unwrap(), [false, true]);
+    }
+}
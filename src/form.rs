@@ -0,0 +1,832 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+use crate::{
+    request::{Method, Request},
+    url::{ParseError, URL},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Enctype {
+    #[default]
+    UrlEncoded,
+    Multipart,
+    TextPlain,
+}
+
+impl Enctype {
+    fn parse(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "multipart/form-data" => Enctype::Multipart,
+            "text/plain" => Enctype::TextPlain,
+            _ => Enctype::UrlEncoded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlKind {
+    /// text, email, search, number and the other single line inputs, with their type
+    Text(String),
+    Password,
+    Hidden,
+    Checkbox,
+    Radio,
+    File,
+    Submit,
+    Image,
+    Reset,
+    Button,
+    TextArea,
+    Select {
+        multiple: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectOption {
+    pub value: String,
+    pub label: String,
+    pub selected: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Control {
+    pub name: String,
+    pub kind: ControlKind,
+    pub value: String,
+    /// For checkboxes and radio buttons
+    pub checked: bool,
+    pub disabled: bool,
+    /// For selects
+    pub options: Vec<SelectOption>,
+    /// For file inputs, the chosen file's name and contents
+    pub file: Option<(String, Vec<u8>)>,
+}
+
+impl Control {
+    fn new(name: String, kind: ControlKind) -> Self {
+        Self {
+            name,
+            kind,
+            value: String::new(),
+            checked: false,
+            disabled: false,
+            options: Vec::new(),
+            file: None,
+        }
+    }
+
+    fn is_button(&self) -> bool {
+        matches!(
+            self.kind,
+            ControlKind::Submit | ControlKind::Image | ControlKind::Reset | ControlKind::Button
+        )
+    }
+}
+
+/// A value in a form's entry list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormValue {
+    Text(String),
+    File { filename: String, contents: Vec<u8> },
+}
+
+/// A `<form>` and its controls, as found in a page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form {
+    pub action: Option<String>,
+    pub method: Method,
+    pub enctype: Enctype,
+    pub controls: Vec<Control>,
+}
+
+impl Form {
+    /// Every form in a page, in document order
+    pub fn parse_all(html: &str) -> Vec<Form> {
+        let mut forms = Vec::new();
+        let mut form: Option<Form> = None;
+        // the select or textarea whose contents are being read
+        let mut open: Option<Control> = None;
+        // whether the option being read had a value attribute, if not its label is its value
+        let mut option_has_value = false;
+        let mut text = String::new();
+
+        for token in tokenize(html) {
+            match token {
+                Token::Text(t) => text.push_str(&t),
+                Token::Start { name, attributes } => {
+                    let attribute = |key: &str| attributes.get(key).cloned();
+                    let disabled = attributes.contains_key("disabled");
+                    match name.as_str() {
+                        "form" => {
+                            if let Some(done) = form.take() {
+                                forms.push(done);
+                            }
+                            form = Some(Form {
+                                action: attribute("action"),
+                                method: match attribute("method") {
+                                    Some(method) if method.eq_ignore_ascii_case("post") => {
+                                        Method::Post
+                                    }
+                                    _ => Method::Get,
+                                },
+                                enctype: attribute("enctype")
+                                    .map_or(Enctype::UrlEncoded, |e| Enctype::parse(&e)),
+                                controls: Vec::new(),
+                            });
+                        }
+                        "input" | "button" => {
+                            let Some(form) = form.as_mut() else { continue };
+                            let default_type = if name == "button" { "submit" } else { "text" };
+                            let kind = match attribute("type")
+                                .unwrap_or(default_type.to_string())
+                                .to_ascii_lowercase()
+                                .as_str()
+                            {
+                                "password" => ControlKind::Password,
+                                "hidden" => ControlKind::Hidden,
+                                "checkbox" => ControlKind::Checkbox,
+                                "radio" => ControlKind::Radio,
+                                "file" => ControlKind::File,
+                                "submit" => ControlKind::Submit,
+                                "image" => ControlKind::Image,
+                                "reset" => ControlKind::Reset,
+                                "button" => ControlKind::Button,
+                                other => ControlKind::Text(other.to_string()),
+                            };
+                            let mut control =
+                                Control::new(attribute("name").unwrap_or_default(), kind);
+                            control.value = attribute("value").unwrap_or_default();
+                            if matches!(control.kind, ControlKind::Checkbox | ControlKind::Radio)
+                                && !attributes.contains_key("value")
+                            {
+                                control.value = "on".to_string();
+                            }
+                            control.checked = attributes.contains_key("checked");
+                            control.disabled = disabled;
+                            form.controls.push(control);
+                        }
+                        "select" | "textarea" if form.is_some() => {
+                            let kind = if name == "select" {
+                                ControlKind::Select {
+                                    multiple: attributes.contains_key("multiple"),
+                                }
+                            } else {
+                                ControlKind::TextArea
+                            };
+                            let mut control =
+                                Control::new(attribute("name").unwrap_or_default(), kind);
+                            control.disabled = disabled;
+                            open = Some(control);
+                            text.clear();
+                        }
+                        "option" => {
+                            finish_option(&mut open, &mut text, option_has_value);
+                            if let Some(select) = open.as_mut() {
+                                select.options.push(SelectOption {
+                                    value: attribute("value").unwrap_or_default(),
+                                    label: String::new(),
+                                    selected: attributes.contains_key("selected"),
+                                });
+                            }
+                            option_has_value = attributes.contains_key("value");
+                            text.clear();
+                        }
+                        _ => {}
+                    }
+                }
+                Token::End(name) => match name.as_str() {
+                    "option" => finish_option(&mut open, &mut text, option_has_value),
+                    "select" | "textarea" => {
+                        finish_option(&mut open, &mut text, option_has_value);
+                        if let (Some(mut control), Some(form)) = (open.take(), form.as_mut()) {
+                            if control.kind == ControlKind::TextArea {
+                                // a newline straight after <textarea> is ignored
+                                let value = text.strip_prefix('\n').unwrap_or(&text);
+                                control.value = value.to_string();
+                            }
+                            form.controls.push(control);
+                        }
+                    }
+                    "form" => {
+                        if let Some(done) = form.take() {
+                            forms.push(done);
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        }
+        forms.extend(form);
+        forms
+    }
+
+    pub fn control(&self, name: &str) -> Option<&Control> {
+        self.controls.iter().find(|control| control.name == name)
+    }
+
+    /// Fills in a field. Text-like fields and textareas take the value, selects select the
+    /// option with that value (adding to the selection if multiple), radio buttons check the one
+    /// with that value and checkboxes with that value get checked
+    pub fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        // only touch radio buttons once we know one of them takes this value
+        let has_radio = self
+            .controls
+            .iter()
+            .any(|c| c.name == name && c.kind == ControlKind::Radio && c.value == value);
+        let mut found = false;
+        for control in self.controls.iter_mut().filter(|c| c.name == name) {
+            match &control.kind {
+                ControlKind::Text(_)
+                | ControlKind::Password
+                | ControlKind::Hidden
+                | ControlKind::TextArea => {
+                    control.value = value.to_string();
+                    return Ok(());
+                }
+                ControlKind::Select { multiple } => {
+                    let multiple = *multiple;
+                    let i = control
+                        .options
+                        .iter()
+                        .position(|option| option.value == value)
+                        .with_context(|| format!("{} has no option {}", name, value))?;
+                    for (j, option) in control.options.iter_mut().enumerate() {
+                        if j == i {
+                            option.selected = true;
+                        } else if !multiple {
+                            option.selected = false;
+                        }
+                    }
+                    return Ok(());
+                }
+                ControlKind::Radio if has_radio => {
+                    control.checked = control.value == value;
+                    found |= control.checked;
+                }
+                ControlKind::Checkbox if control.value == value => {
+                    control.checked = true;
+                    found = true;
+                }
+                _ => {}
+            }
+        }
+        anyhow::ensure!(found, "no field {} accepting {}", name, value);
+        Ok(())
+    }
+
+    /// Checks or unchecks every checkbox with this name
+    pub fn check(&mut self, name: &str, checked: bool) -> anyhow::Result<()> {
+        let mut found = false;
+        for control in self.controls.iter_mut() {
+            if control.name == name && control.kind == ControlKind::Checkbox {
+                control.checked = checked;
+                found = true;
+            }
+        }
+        anyhow::ensure!(found, "no checkbox {}", name);
+        Ok(())
+    }
+
+    /// Attaches a file to a file input
+    pub fn set_file(
+        &mut self,
+        name: &str,
+        filename: &str,
+        contents: impl Into<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let control = self
+            .controls
+            .iter_mut()
+            .find(|c| c.name == name && c.kind == ControlKind::File)
+            .with_context(|| format!("no file input {}", name))?;
+        control.file = Some((filename.to_string(), contents.into()));
+        Ok(())
+    }
+
+    /// The name/value pairs the form would submit. `submitter` is the name of the submit button
+    /// that was pressed, if any
+    pub fn entries(&self, submitter: Option<&str>) -> Vec<(String, FormValue)> {
+        let mut entries = Vec::new();
+        let text = |name: &str, value: &str| (name.to_string(), FormValue::Text(value.to_string()));
+        for control in &self.controls {
+            if control.disabled || control.name.is_empty() && control.kind != ControlKind::Image {
+                continue;
+            }
+            if control.is_button() {
+                let pressed = submitter.is_some_and(|name| name == control.name);
+                match control.kind {
+                    ControlKind::Submit if pressed => {
+                        entries.push(text(&control.name, &control.value))
+                    }
+                    ControlKind::Image if pressed => {
+                        let prefix = if control.name.is_empty() {
+                            String::new()
+                        } else {
+                            format!("{}.", control.name)
+                        };
+                        entries.push(text(&format!("{}x", prefix), "0"));
+                        entries.push(text(&format!("{}y", prefix), "0"));
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match &control.kind {
+                ControlKind::Checkbox | ControlKind::Radio => {
+                    if control.checked {
+                        entries.push(text(&control.name, &control.value));
+                    }
+                }
+                ControlKind::Select { multiple } => {
+                    let selected: Vec<&SelectOption> =
+                        control.options.iter().filter(|o| o.selected).collect();
+                    match (selected.is_empty(), control.options.first()) {
+                        // a single select with nothing selected shows, and submits, the first
+                        (true, Some(first)) if !multiple => {
+                            entries.push(text(&control.name, &first.value))
+                        }
+                        _ => entries.extend(
+                            selected
+                                .into_iter()
+                                .map(|option| text(&control.name, &option.value)),
+                        ),
+                    }
+                }
+                ControlKind::File => {
+                    let (filename, contents) = control.file.clone().unwrap_or_default();
+                    entries.push((control.name.clone(), FormValue::File { filename, contents }));
+                }
+                _ => entries.push(text(&control.name, &control.value)),
+            }
+        }
+        entries
+    }
+
+    /// Builds the request submitting this form from the page at `base`, honoring the form's
+    /// action, method and enctype
+    pub fn submit(&self, base: &URL, submitter: Option<&str>) -> Result<Request, ParseError> {
+        let action = match self.action.as_deref() {
+            Some(action) if !action.is_empty() => base.join(action)?,
+            _ => base.clone(),
+        };
+        let entries = self.entries(submitter);
+
+        if self.method == Method::Get {
+            let url = action.to_string();
+            let url = url.split_once('#').map_or(url.as_str(), |(url, _)| url);
+            let url = url.split_once('?').map_or(url, |(url, _)| url);
+            let query = urlencode(&entries);
+            return Ok(Request::get(format!("{}?{}", url, query).parse()?));
+        }
+
+        let request = Request::post(action);
+        Ok(match self.enctype {
+            Enctype::UrlEncoded => request
+                .with_header("Content-Type", "application/x-www-form-urlencoded")
+                .with_body(urlencode(&entries)),
+            Enctype::Multipart => {
+                let boundary = boundary();
+                request
+                    .with_header(
+                        "Content-Type",
+                        format!("multipart/form-data; boundary={}", boundary),
+                    )
+                    .with_body(multipart(&entries, &boundary))
+            }
+            Enctype::TextPlain => {
+                let mut body = String::new();
+                for (name, value) in &entries {
+                    let value = match value {
+                        FormValue::Text(value) => value,
+                        FormValue::File { filename, .. } => filename,
+                    };
+                    let _ = write!(body, "{}={}\r\n", name, value);
+                }
+                request
+                    .with_header("Content-Type", "text/plain;charset=UTF-8")
+                    .with_body(body)
+            }
+        })
+    }
+}
+
+/// Gives the option being read its label, and its value if it had none
+fn finish_option(open: &mut Option<Control>, text: &mut String, has_value: bool) {
+    let Some(option) = open.as_mut().and_then(|select| select.options.last_mut()) else {
+        return;
+    };
+    if option.label.is_empty() {
+        option.label = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !has_value {
+            option.value = option.label.clone();
+        }
+    }
+    text.clear();
+}
+
+/// application/x-www-form-urlencoded, files contribute their filename
+pub fn urlencode(entries: &[(String, FormValue)]) -> String {
+    entries
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                FormValue::Text(value) => value,
+                FormValue::File { filename, .. } => filename,
+            };
+            format!(
+                "{}={}",
+                urlencode_component(name),
+                urlencode_component(value)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn urlencode_component(s: &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            byte => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+fn multipart(entries: &[(String, FormValue)], boundary: &str) -> Vec<u8> {
+    // quotes and newlines cant appear raw inside the quoted names
+    let escape = |s: &str| {
+        s.replace('"', "%22")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    let mut body = Vec::new();
+    for (name, value) in entries {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        match value {
+            FormValue::Text(value) => {
+                body.extend_from_slice(
+                    format!(
+                        "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                        escape(name)
+                    )
+                    .as_bytes(),
+                );
+                body.extend_from_slice(value.as_bytes());
+            }
+            FormValue::File { filename, contents } => {
+                body.extend_from_slice(
+                    format!(
+                        concat!(
+                            "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                            "Content-Type: application/octet-stream\r\n\r\n"
+                        ),
+                        escape(name),
+                        escape(filename)
+                    )
+                    .as_bytes(),
+                );
+                body.extend_from_slice(contents);
+            }
+        }
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos());
+    format!(
+        "----browser_rust{:x}{:x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+enum Token {
+    Text(String),
+    Start {
+        name: String,
+        attributes: HashMap<String, String>,
+    },
+    End(String),
+}
+
+/// Just enough of an html tokenizer for forms: tags with their attributes and decoded text,
+/// skipping comments, doctypes and the contents of scripts and styles
+fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let Some(lt) = rest.find('<') else {
+            tokens.push(Token::Text(decode_entities(rest)));
+            break;
+        };
+        if lt > 0 {
+            tokens.push(Token::Text(decode_entities(&rest[..lt])));
+        }
+        rest = &rest[lt..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(gt) = tag_end(rest) else {
+            tokens.push(Token::Text(decode_entities(rest)));
+            break;
+        };
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        if tag.starts_with('!') || tag.starts_with('?') {
+            continue;
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            tokens.push(Token::End(name.trim().to_ascii_lowercase()));
+            continue;
+        }
+        let (name, attributes) = parse_tag(tag);
+        // script and style contents arent markup, and neither are textarea and title contents
+        // though those can have entities
+        let raw = matches!(name.as_str(), "script" | "style" | "textarea" | "title");
+        let escapable = matches!(name.as_str(), "textarea" | "title");
+        tokens.push(Token::Start {
+            name: name.clone(),
+            attributes,
+        });
+        if raw {
+            let close = format!("</{}", name);
+            let end = rest.to_ascii_lowercase().find(&close).unwrap_or(rest.len());
+            if escapable {
+                tokens.push(Token::Text(decode_entities(&rest[..end])));
+            }
+            rest = &rest[end..];
+        }
+    }
+    tokens
+}
+
+/// The index of the `>` closing the tag at the start of `s`, skipping quoted attribute values
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_tag(tag: &str) -> (String, HashMap<String, String>) {
+    let tag = tag.trim_end_matches('/');
+    let name_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+    let name = tag[..name_end].to_ascii_lowercase();
+    let mut attributes = HashMap::new();
+
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_ascii_lowercase();
+        rest = rest[key_end..].trim_start();
+
+        let value = if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (value, remaining) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let inner = &after[1..];
+                    let end = inner.find(quote).unwrap_or(inner.len());
+                    (&inner[..end], inner.get(end + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let end = after
+                        .find(|c: char| c.is_whitespace())
+                        .unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            rest = remaining.trim_start();
+            decode_entities(value)
+        } else {
+            String::new()
+        };
+        if !key.is_empty() {
+            // the first of a repeated attribute wins
+            attributes.entry(key).or_insert(value);
+        }
+    }
+    (name, attributes)
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let replacement = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, replacement) {
+            (Some(entity), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: &str = r#"
+        <html><body>
+        <form id="search" action="/search">
+            <input name=q value="rust &amp; tls">
+            <input type="submit" name="go" value="Go">
+        </form>
+        <!-- <form action="/commented-out"> -->
+        <form action="signup?ref=1" method="POST" enctype="multipart/form-data">
+            <input type="hidden" name="token" value="abc">
+            <input type="text" name="user">
+            <input type="password" name="pass" disabled>
+            <input type="checkbox" name="news" checked>
+            <input type="checkbox" name="terms" value="yes">
+            <input type="radio" name="plan" value="free" checked>
+            <input type="radio" name="plan" value="pro">
+            <select name="country">
+                <option value="nz">New Zealand
+                <option selected>Other Place</option>
+            </select>
+            <select name="tags" multiple>
+                <option value="a">A</option>
+                <option value="b" selected>B</option>
+            </select>
+            <textarea name="bio">
+Hello <there></textarea>
+            <input type="file" name="avatar">
+            <button name="submit" value="signup">Sign up</button>
+            <button type="reset">Reset</button>
+        </form>
+        </body></html>
+    "#;
+
+    #[test]
+    fn parses_controls() {
+        let forms = Form::parse_all(PAGE);
+        assert_eq!(forms.len(), 2);
+
+        let search = &forms[0];
+        assert_eq!(search.action.as_deref(), Some("/search"));
+        assert_eq!(search.method, Method::Get);
+        assert_eq!(search.control("q").unwrap().value, "rust & tls");
+
+        let signup = &forms[1];
+        assert_eq!(signup.method, Method::Post);
+        assert_eq!(signup.enctype, Enctype::Multipart);
+        assert!(signup.control("pass").unwrap().disabled);
+        assert_eq!(signup.control("news").unwrap().value, "on");
+        let country = signup.control("country").unwrap();
+        assert_eq!(country.options[0].value, "nz");
+        assert_eq!(country.options[0].label, "New Zealand");
+        assert_eq!(country.options[1].value, "Other Place");
+        assert!(country.options[1].selected);
+        assert_eq!(signup.control("bio").unwrap().value, "Hello <there>");
+        assert_eq!(signup.control("submit").unwrap().kind, ControlKind::Submit);
+    }
+
+    #[test]
+    fn fills_and_lists_entries() {
+        let mut signup = Form::parse_all(PAGE).remove(1);
+        signup.set("user", "ana").unwrap();
+        signup.set("plan", "pro").unwrap();
+        signup.set("country", "nz").unwrap();
+        signup.set("tags", "a").unwrap();
+        signup.check("news", false).unwrap();
+        signup.set("terms", "yes").unwrap();
+        signup
+            .set_file("avatar", "me.png", b"PNG".to_vec())
+            .unwrap();
+        assert!(signup.set("plan", "enterprise").is_err());
+        assert!(signup.set("country", "mars").is_err());
+
+        let text = |name: &str, value: &str| (name.to_string(), FormValue::Text(value.into()));
+        assert_eq!(
+            signup.entries(Some("submit")),
+            vec![
+                text("token", "abc"),
+                text("user", "ana"),
+                text("terms", "yes"),
+                text("plan", "pro"),
+                text("country", "nz"),
+                text("tags", "a"),
+                text("tags", "b"),
+                text("bio", "Hello <there>"),
+                (
+                    "avatar".to_string(),
+                    FormValue::File {
+                        filename: "me.png".to_string(),
+                        contents: b"PNG".to_vec()
+                    }
+                ),
+                text("submit", "signup"),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_submission_replaces_query() {
+        let search = Form::parse_all(PAGE).remove(0);
+        let base: URL = "http://example.com/page?old=1".parse().unwrap();
+        let request = search.submit(&base, Some("go")).unwrap();
+        assert_eq!(request.method(), Method::Get);
+        assert_eq!(
+            request.url().to_string(),
+            "http://example.com/search?q=rust+%26+tls&go=Go"
+        );
+    }
+
+    #[test]
+    fn post_submissions() {
+        let base: URL = "http://example.com/account/".parse().unwrap();
+        let mut form = Form::parse_all(PAGE).remove(1);
+        form.set_file("avatar", "a\"b.txt", b"hi".to_vec()).unwrap();
+
+        let request = form.submit(&base, None).unwrap();
+        assert_eq!(request.method(), Method::Post);
+        assert_eq!(
+            request.url().to_string(),
+            "http://example.com/account/signup?ref=1"
+        );
+        let content_type = request.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let body = String::from_utf8(request.body().to_vec()).unwrap();
+        assert!(body.starts_with(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"token\"\r\n\r\nabc\r\n",
+            boundary
+        )));
+        assert!(body.contains(
+            "name=\"avatar\"; filename=\"a%22b.txt\"\r\nContent-Type: application/octet-stream\r\n\r\nhi\r\n"
+        ));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+
+        form.enctype = Enctype::UrlEncoded;
+        let request = form.submit(&base, None).unwrap();
+        assert_eq!(
+            request.header("Content-Type"),
+            Some("application/x-www-form-urlencoded")
+        );
+        assert_eq!(
+            request.body(),
+            b"token=abc&user=&news=on&plan=free&country=Other+Place&tags=b&bio=Hello+%3Cthere%3E&avatar=a%22b.txt"
+        );
+    }
+}
@@ -1,4 +1,8 @@
+pub mod cookie;
 pub mod engine;
+pub mod form;
+pub mod netrc;
 pub mod parser;
+pub mod request;
 pub mod response;
 pub mod url;
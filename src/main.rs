@@ -1,20 +1,284 @@
-use std::{env::args, process::exit};
+use std::{env::args, path::PathBuf, process::exit, time::Duration};
 
-use browser_rust::{engine::fetch, parser::parse, url::URL};
+use anyhow::Context;
+
+use browser_rust::{
+    engine::{Batch, Credentials, Download, Engine, Proxies, Proxy, TlsConfig},
+    netrc::Netrc,
+    parser::parse,
+    request::{Method, Request},
+    url::URL,
+};
+use cache::Cache;
+
+const USAGE: &str = "\
+usage: browser_rust [options] <url>...
+       browser_rust download [options] [-o FILE] [--dir DIR] <url>
+
+options:
+  --offline                 only serve pages from the cache
+  --cookie-jar FILE         load and save cookies in a cookies.txt file
+  -X, --request METHOD      http method, GET by default
+  -H, --header 'NAME: VAL'  extra request header, may be repeated
+  -d, --data DATA           request body, makes the default method POST
+  --connect-timeout SECS    give up connecting after this many seconds
+  -m, --max-time SECS       give up on the whole fetch after this many seconds
+  --parallel N              fetch up to N of several urls at once, 8 by default
+  --per-host N              fetch up to N urls from one host at once, 2 by default
+  --delay SECS              wait this long between fetches from one host
+  -x, --proxy URL           go through this http, socks5 or socks5h proxy rather than
+                            HTTP_PROXY/HTTPS_PROXY/ALL_PROXY
+  --proxy-user USER:PASS    username and password for the proxy
+  --noproxy HOSTS           comma separated hosts to reach directly, overriding NO_PROXY
+  -u, --user USER:PASS      answer a 401 with basic or digest auth
+  --oauth2-bearer TOKEN     answer a 401 with a bearer token
+  --netrc                   answer a 401 with logins from ~/.netrc or $NETRC
+  --netrc-file FILE         answer a 401 with logins from this netrc file
+  --cacert FILE             also trust the CA certificates in this PEM file
+  --ca-native               also trust the system's CA certificates
+  -k, --insecure            dont verify https certificates, for testing only
+  --cert FILE               present this PEM client certificate chain for mutual tls
+  --key FILE                the client certificate's PEM private key, otherwise read
+                            from the --cert file
+  -o, --output FILE         download to this file instead of the server's name for it
+  --dir DIR                 download into this directory";
+
+/// Removes a flag from the args, returning whether it was there
+fn take_flag(args: &mut Vec<String>, names: &[&str]) -> bool {
+    match args.iter().position(|arg| names.contains(&arg.as_str())) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes an option and its value from the args
+fn take_option(args: &mut Vec<String>, names: &[&str]) -> anyhow::Result<Option<String>> {
+    let Some(i) = args.iter().position(|arg| names.contains(&arg.as_str())) else {
+        return Ok(None);
+    };
+    let value = args
+        .get(i + 1)
+        .with_context(|| format!("{} needs a value", names.join("/")))?
+        .clone();
+    args.drain(i..=i + 1);
+    Ok(Some(value))
+}
+
+/// Removes an option holding a number of seconds, which may be fractional
+fn take_seconds(args: &mut Vec<String>, names: &[&str]) -> anyhow::Result<Option<Duration>> {
+    take_option(args, names)?
+        .map(|secs| {
+            let secs: f64 = secs
+                .parse()
+                .with_context(|| format!("{} takes a number of seconds", names.join("/")))?;
+            Duration::try_from_secs_f64(secs)
+                .with_context(|| format!("{} takes a number of seconds", names.join("/")))
+        })
+        .transpose()
+}
 
 fn main() -> anyhow::Result<()> {
-    let Some(url) = args().nth(1) else {
-        eprintln!("Must supply a url as cmd line arg");
+    let mut args: Vec<String> = args().skip(1).collect();
+    let download = args.first().is_some_and(|arg| arg == "download");
+    if download {
+        args.remove(0);
+    }
+    let output = take_option(&mut args, &["-o", "--output"])?;
+    let dir = take_option(&mut args, &["--dir"])?;
+    if !download && (output.is_some() || dir.is_some()) {
+        anyhow::bail!("-o/--output and --dir only work with download");
+    }
+    let offline = take_flag(&mut args, &["--offline"]);
+    let cookie_jar = take_option(&mut args, &["--cookie-jar"])?.map(PathBuf::from);
+    let method = take_option(&mut args, &["-X", "--request"])?;
+    let data = take_option(&mut args, &["-d", "--data"])?;
+    let connect_timeout = take_seconds(&mut args, &["--connect-timeout"])?;
+    let max_time = take_seconds(&mut args, &["-m", "--max-time"])?;
+    let parallel = take_option(&mut args, &["--parallel"])?;
+    let per_host = take_option(&mut args, &["--per-host"])?;
+    let delay = take_seconds(&mut args, &["--delay"])?;
+    let proxy = take_option(&mut args, &["-x", "--proxy"])?;
+    let proxy_user = take_option(&mut args, &["--proxy-user"])?;
+    let no_proxy = take_option(&mut args, &["--noproxy"])?;
+    let user = take_option(&mut args, &["-u", "--user"])?;
+    let bearer = take_option(&mut args, &["--oauth2-bearer"])?;
+    let use_netrc = take_flag(&mut args, &["--netrc"]);
+    let netrc_file = take_option(&mut args, &["--netrc-file"])?.map(PathBuf::from);
+    let cacert = take_option(&mut args, &["--cacert"])?.map(PathBuf::from);
+    let ca_native = take_flag(&mut args, &["--ca-native"]);
+    let insecure = take_flag(&mut args, &["-k", "--insecure"]);
+    let cert = take_option(&mut args, &["--cert"])?.map(PathBuf::from);
+    let key = take_option(&mut args, &["--key"])?.map(PathBuf::from);
+    let mut headers = Vec::new();
+    while let Some(header) = take_option(&mut args, &["-H", "--header"])? {
+        let (name, value) = header
+            .split_once(':')
+            .context("headers look like 'Name: value'")?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
         exit(1);
+    }
+    let urls = args
+        .iter()
+        .map(|url| url.parse())
+        .collect::<Result<Vec<URL>, _>>()?;
+    if (download || urls.len() == 1)
+        && (parallel.is_some() || per_host.is_some() || delay.is_some())
+    {
+        anyhow::bail!("--parallel, --per-host and --delay only work with several urls");
+    }
+    if key.is_some() && cert.is_none() {
+        anyhow::bail!("--key needs --cert");
+    }
+
+    let method = match (method, &data) {
+        (Some(method), _) => method.parse()?,
+        (None, Some(_)) => Method::Post,
+        (None, None) => Method::Get,
     };
-    let url: URL = url.parse()?;
-    let res = fetch(&url)?;
-    let parsed = parse(res)?;
-
-    print!("{}", &parsed);
-    //let parser = HttpResponseParser::parse(&res)?;
-    //let html = HTMLParser::parse(&parser.body());
-    //println!("{}", html);
-    //
+    let requests: Vec<Request> = urls
+        .into_iter()
+        .map(|url| {
+            let mut request = Request::new(method, url);
+            for (name, value) in &headers {
+                request = request.with_header(name, value);
+            }
+            if let Some(data) = &data {
+                request = request.with_body(data.as_str());
+            }
+            request
+        })
+        .collect();
+
+    let mut engine = Engine::new();
+    if let Some(dir) = cache::default_dir() {
+        engine = engine.with_cache(Cache::open(dir));
+    }
+    if offline {
+        engine = engine.offline();
+    }
+    let mut proxies = Proxies::from_env().context("bad proxy in the environment")?;
+    if let Some(proxy) = proxy {
+        let mut proxy: Proxy = proxy.parse().context("bad --proxy")?;
+        if let Some(user) = proxy_user {
+            let (user, password) = user.split_once(':').unwrap_or((&user, ""));
+            proxy = proxy.with_credentials(user, password);
+        }
+        proxies = proxies.with_http(proxy.clone()).with_https(proxy);
+    }
+    if let Some(no_proxy) = no_proxy {
+        proxies = proxies.with_no_proxy(&no_proxy);
+    }
+    engine = engine.with_proxies(proxies);
+    if let Some(user) = user {
+        let (user, password) = user.split_once(':').unwrap_or((&user, ""));
+        engine = engine.with_credentials(Credentials::password(user, password));
+    } else if let Some(token) = bearer {
+        engine = engine.with_credentials(Credentials::bearer(token));
+    }
+    let netrc_file = netrc_file.or_else(|| use_netrc.then(Netrc::default_path).flatten());
+    if let Some(path) = netrc_file {
+        let netrc =
+            Netrc::load(&path).with_context(|| format!("couldnt read {}", path.display()))?;
+        engine = engine.with_netrc(netrc);
+    }
+    if cacert.is_some() || ca_native || insecure || cert.is_some() {
+        let mut tls = TlsConfig::new()
+            .with_system_roots(ca_native)
+            .with_insecure(insecure);
+        if let Some(path) = &cacert {
+            tls = tls.with_pem_roots_file(path).context("bad --cacert")?;
+        }
+        if let Some(cert) = &cert {
+            let key = key.as_ref().unwrap_or(cert);
+            tls = tls
+                .with_client_cert_files(cert, key)
+                .context("bad --cert or --key")?;
+        }
+        engine = engine.with_tls_config(tls.build()?);
+    }
+    if let Some(timeout) = connect_timeout {
+        engine = engine.with_connect_timeout(timeout);
+    }
+    if let Some(timeout) = max_time {
+        engine = engine.with_timeout(timeout);
+    }
+    if let Some(path) = &cookie_jar
+        && path.exists()
+    {
+        engine.cookies().load(path)?;
+    }
+    if download {
+        if requests.len() != 1 {
+            anyhow::bail!("download takes one url");
+        }
+        let mut download = Download::new(requests[0].url().clone()).with_progress(|progress| {
+            match progress.total {
+                Some(total) => eprint!("\r{}/{} bytes", progress.received, total),
+                None => eprint!("\r{} bytes", progress.received),
+            }
+        });
+        if let Some(output) = output {
+            download = download.with_file(output);
+        }
+        if let Some(dir) = dir {
+            download = download.with_dir(dir);
+        }
+        let downloaded = engine.download(download)?;
+        if let Some(path) = &cookie_jar {
+            engine.cookies().save(path)?;
+        }
+        eprintln!();
+        println!(
+            "saved {} ({} bytes)",
+            downloaded.path.display(),
+            downloaded.bytes
+        );
+        return Ok(());
+    }
+    if requests.len() == 1 {
+        let request = requests.into_iter().next().context("no url")?;
+        let res = engine.send(request)?;
+        if let Some(path) = &cookie_jar {
+            engine.cookies().save(path)?;
+        }
+        let parsed = parse(res)?;
+
+        print!("{}", &parsed);
+        return Ok(());
+    }
+
+    let mut batch = Batch::new();
+    if let Some(parallel) = parallel {
+        batch = batch.with_concurrency(parallel.parse().context("--parallel takes a number")?);
+    }
+    if let Some(per_host) = per_host {
+        batch = batch.with_per_host_limit(per_host.parse().context("--per-host takes a number")?);
+    }
+    if let Some(delay) = delay {
+        batch = batch.with_delay(delay);
+    }
+    let mut failed = false;
+    engine.send_batch(&batch, requests, |result| {
+        println!("==> {} <==", result.url);
+        match result.result.map_err(anyhow::Error::from).and_then(parse) {
+            Ok(parsed) => println!("{}", parsed),
+            Err(err) => {
+                eprintln!("{}: {:#}", result.url, err);
+                failed = true;
+            }
+        }
+    });
+    if let Some(path) = &cookie_jar {
+        engine.cookies().save(path)?;
+    }
+    if failed {
+        exit(1);
+    }
     Ok(())
 }
@@ -0,0 +1,137 @@
+//! Logins from a `.netrc` file, as curl and ftp read them
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    pub login: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Netrc {
+    machines: Vec<(String, Login)>,
+    default: Option<Login>,
+}
+
+impl Netrc {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// `$NETRC`, otherwise `.netrc` in the home directory
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("NETRC") {
+            return Some(PathBuf::from(path));
+        }
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc"))
+    }
+
+    /// Lenient like curl, tokens it doesnt know are skipped, as are macros
+    pub fn parse(contents: &str) -> Self {
+        let mut netrc = Netrc::default();
+        let mut current: Option<Entry> = None;
+        let mut lines = contents.lines();
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" => {
+                        netrc.add(current.take());
+                        let machine = match token {
+                            "machine" => Some(tokens.next().unwrap_or_default().to_string()),
+                            _ => None,
+                        };
+                        current = Some(Entry {
+                            machine,
+                            ..Entry::default()
+                        });
+                    }
+                    "login" | "password" | "account" => {
+                        let value = tokens.next().unwrap_or_default().to_string();
+                        match (&mut current, token) {
+                            (Some(entry), "login") => entry.login = value,
+                            (Some(entry), "password") => entry.password = value,
+                            _ => {}
+                        }
+                    }
+                    "macdef" => {
+                        // a macro runs until the next blank line
+                        for line in lines.by_ref() {
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        netrc.add(current);
+        netrc
+    }
+
+    fn add(&mut self, entry: Option<Entry>) {
+        let Some(entry) = entry else {
+            return;
+        };
+        let login = Login {
+            login: entry.login,
+            password: entry.password,
+        };
+        match entry.machine {
+            Some(machine) => self.machines.push((machine, login)),
+            // only the first default counts
+            None => {
+                self.default.get_or_insert(login);
+            }
+        }
+    }
+
+    /// The first entry for the host, otherwise the default
+    pub fn login(&self, host: &str) -> Option<&Login> {
+        self.machines
+            .iter()
+            .find(|(machine, _)| machine.eq_ignore_ascii_case(host))
+            .map(|(_, login)| login)
+            .or(self.default.as_ref())
+    }
+}
+
+/// An entry as it is read, default has no machine
+#[derive(Default)]
+struct Entry {
+    machine: Option<String>,
+    login: String,
+    password: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_machines_and_default() {
+        let netrc = Netrc::parse(
+            "machine intranet.corp login alice password s3cret\n\
+             macdef init\n\
+             machine ignored login x password y\n\
+             \n\
+             machine Other.Corp\n  login bob\n  account acct\n  password hunter2\n\
+             default login anonymous password guest\n",
+        );
+        let login = |host| {
+            netrc
+                .login(host)
+                .map(|login| (login.login.as_str(), login.password.as_str()))
+        };
+        assert_eq!(login("intranet.corp"), Some(("alice", "s3cret")));
+        assert_eq!(login("other.corp"), Some(("bob", "hunter2")));
+        assert_eq!(login("ignored"), Some(("anonymous", "guest")));
+        assert_eq!(Netrc::parse("").login("anything"), None);
+    }
+}
@@ -1,18 +1,17 @@
-use std::{collections::HashMap, fmt::Display, rc::Rc};
+use std::{collections::HashMap, fmt::Display};
 use thiserror::Error;
 
-use crate::response::Response;
+use crate::response::{Response, Source};
 
 pub fn parse(response: Response) -> anyhow::Result<Box<dyn Display>> {
-    match response {
-        Response::Http(res) => {
-            let res = Rc::new(res);
-            Ok(Box::new(HttpResponseParser::parse(&res)?))
+    match response.source() {
+        Source::Http => Ok(Box::new(HTMLParser::parse(&response.text()))),
+        Source::File => Ok(Box::new(response.text().into_owned())),
+        Source::Data => {
+            let data = response.url().data().unwrap_or_default();
+            Ok(Box::new(DataResponseParser::parse(data)?))
         }
-        Response::File(res) => Ok(Box::new(res)),
-        Response::Data(res) => Ok(Box::new(DataResponseParser::parse(&res)?)),
-        Response::ViewSource(res) => Ok(Box::new(ViewSourceResponseParser::parse(*res)?)),
-        Response::None => todo!(),
+        Source::ViewSource => Ok(Box::new(ViewSourceResponseParser::parse(response)?)),
     }
 }
 
@@ -22,18 +21,9 @@ pub struct ViewSourceResponseParser {
 
 impl ViewSourceResponseParser {
     pub fn parse(response: Response) -> anyhow::Result<Self> {
-        match response {
-            Response::Http(ref res) => {
-                let parser = HttpResponseParser::parse(res)?;
-                Ok(Self {
-                    source: parser.body().to_string(),
-                })
-            }
-            Response::File(_) => unimplemented!(),
-            Response::Data(_) => unimplemented!(),
-            Response::ViewSource(response) => Self::parse(*response),
-            Response::None => unimplemented!(),
-        }
+        Ok(Self {
+            source: response.text().into_owned(),
+        })
     }
 }
 
@@ -92,12 +82,17 @@ pub enum HttpResponseParseError {
 
     #[error("malformed header in response")]
     MalformedHeader,
+
+    #[error("malformed chunked body in response")]
+    MalformedChunk,
 }
 pub struct HttpResponseParser {
     http_version: String,
     status: u32,
     message: String,
     headers: HashMap<String, String>,
+    // every header line in order, as headers like Set-Cookie repeat and cant be merged
+    header_list: Vec<(String, String)>,
     body: String,
 }
 
@@ -116,10 +111,15 @@ impl HttpResponseParser {
             .split_once("\r\n")
             .ok_or(HttpResponseParseError::MissingStatusMessage)?;
         let mut headers = HashMap::new();
+        let mut header_list = Vec::new();
 
-        let (raw_headers, body) = rest
-            .split_once("\r\n\r\n")
-            .ok_or(HttpResponseParseError::MalformedHeader)?;
+        let (raw_headers, body) = match rest.strip_prefix("\r\n") {
+            // no headers at all
+            Some(body) => ("", body),
+            None => rest
+                .split_once("\r\n\r\n")
+                .ok_or(HttpResponseParseError::MalformedHeader)?,
+        };
 
         for line in raw_headers.split("\r\n") {
             if line.is_empty() {
@@ -130,6 +130,7 @@ impl HttpResponseParser {
                 .split_once(":")
                 .ok_or(HttpResponseParseError::MalformedHeader)?;
             headers.insert(key.to_string(), value.trim().to_string());
+            header_list.push((key.to_string(), value.trim().to_string()));
         }
 
         Ok(HttpResponseParser {
@@ -137,6 +138,7 @@ impl HttpResponseParser {
             status,
             message: message.to_string(),
             headers,
+            header_list,
             body: body.to_string(),
         })
     }
@@ -153,6 +155,24 @@ impl HttpResponseParser {
     pub fn headers_map(&self) -> &HashMap<String, String> {
         &self.headers
     }
+    /// Every header in the order received, including repeats
+    pub fn header_list(&self) -> &[(String, String)] {
+        &self.header_list
+    }
+    /// Every value of a repeated header, ignoring case
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.header_list
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    /// Header lookup ignoring case, as header names are case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
     pub fn http_version(&self) -> &str {
         self.http_version.as_str()
     }
@@ -182,6 +202,6 @@ impl HTMLParser {
             }
         }
 
-        return text;
+        text
     }
 }
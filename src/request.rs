@@ -0,0 +1,336 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use crate::url::{ParseError, URL};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Method {
+    #[default]
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+
+    /// Safe methods dont change anything on the server
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Method::Get | Method::Head | Method::Options)
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("unsupported http method {0}")]
+pub struct UnknownMethod(String);
+
+impl FromStr for Method {
+    type Err = UnknownMethod;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "PATCH" => Ok(Method::Patch),
+            "DELETE" => Ok(Method::Delete),
+            "OPTIONS" => Ok(Method::Options),
+            _ => Err(UnknownMethod(s.to_string())),
+        }
+    }
+}
+
+/// An http request for `engine::send`. Headers set here are sent as given, after the ones the
+/// engine adds itself (Host, Connection, Content-Length), except for User-Agent which replaces
+/// the engine's default
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: Method,
+    url: URL,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    pub fn new(method: Method, url: URL) -> Self {
+        Self {
+            method,
+            url,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(url: URL) -> Self {
+        Self::new(Method::Get, url)
+    }
+
+    pub fn head(url: URL) -> Self {
+        Self::new(Method::Head, url)
+    }
+
+    pub fn post(url: URL) -> Self {
+        Self::new(Method::Post, url)
+    }
+
+    pub fn put(url: URL) -> Self {
+        Self::new(Method::Put, url)
+    }
+
+    pub fn patch(url: URL) -> Self {
+        Self::new(Method::Patch, url)
+    }
+
+    pub fn delete(url: URL) -> Self {
+        Self::new(Method::Delete, url)
+    }
+
+    pub fn options(url: URL) -> Self {
+        Self::new(Method::Options, url)
+    }
+
+    /// Adds a header, keeping any earlier ones with the same name
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Replaces every header with this name
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.into()));
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn url(&self) -> &URL {
+        &self.url
+    }
+
+    pub(crate) fn set_url(&mut self, url: URL) {
+        self.url = url;
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// First value of a header, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The request line and headers as sent on the wire, ending in the blank line.
+    /// `cookie` is the jar's Cookie header, merged with any set on the request
+    pub(crate) fn wire_head(&self, cookie: Option<&str>) -> Result<String, ParseError> {
+        let path = self.url.path().ok_or(ParseError::HostMissing)?;
+        self.head_for(path, cookie, None)
+    }
+
+    /// The head for a plain http request through a proxy, which wants the whole url as the
+    /// target
+    pub(crate) fn proxy_wire_head(
+        &self,
+        cookie: Option<&str>,
+        proxy_authorization: Option<&str>,
+    ) -> Result<String, ParseError> {
+        let host = self.url.host().ok_or(ParseError::HostMissing)?;
+        let path = self.url.path().ok_or(ParseError::HostMissing)?;
+        let target = format!("{}://{}{}", self.url.scheme(), host, path);
+        self.head_for(&target, cookie, proxy_authorization)
+    }
+
+    fn head_for(
+        &self,
+        target: &str,
+        cookie: Option<&str>,
+        proxy_authorization: Option<&str>,
+    ) -> Result<String, ParseError> {
+        let host = self.url.host().ok_or(ParseError::HostMissing)?;
+        // anything that ends a line early would let the rest pass as headers of its own
+        let unsafe_in_line = |s: &str| s.bytes().any(|b| b == b' ' || b.is_ascii_control());
+        if unsafe_in_line(target) {
+            return Err(ParseError::InvalidTarget);
+        }
+        if unsafe_in_line(host) {
+            return Err(ParseError::InvalidHost);
+        }
+
+        let mut head = format!(
+            concat!(
+                "{} {} HTTP/1.1\r\n",
+                "Host: {}\r\n",
+                "Connection: close\r\n",
+            ),
+            self.method, target, host
+        );
+        if let Some(authorization) = proxy_authorization {
+            push_header(&mut head, "Proxy-Authorization", authorization)?;
+        }
+        if self.header("User-Agent").is_none() {
+            head.push_str("User-Agent: browser_rust\r\n");
+        }
+        // a body needs a length, and so do methods that usually carry one
+        if !self.body.is_empty()
+            || matches!(self.method, Method::Post | Method::Put | Method::Patch)
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        let mut cookies: Vec<&str> = Vec::new();
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("Cookie") {
+                cookies.push(value);
+            } else if !name.eq_ignore_ascii_case("Content-Length") {
+                push_header(&mut head, name, value)?;
+            }
+        }
+        cookies.extend(cookie);
+        if !cookies.is_empty() {
+            push_header(&mut head, "Cookie", &cookies.join("; "))?;
+        }
+        head.push_str("\r\n");
+        Ok(head)
+    }
+}
+
+/// Names must be RFC 9110 tokens, and values can have tabs but no CR, LF or other controls,
+/// so neither can add headers or end the head early
+fn push_header(head: &mut String, name: &str, value: &str) -> Result<(), ParseError> {
+    let is_token = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    let is_value = value.bytes().all(|b| b == b'\t' || !b.is_ascii_control());
+    if !is_token || !is_value {
+        return Err(ParseError::InvalidHeader(name.to_string()));
+    }
+    head.push_str(&format!("{}: {}\r\n", name, value));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn methods_round_trip() {
+        for method in [
+            Method::Get,
+            Method::Head,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete,
+            Method::Options,
+        ] {
+            assert_eq!(method.as_str().parse::<Method>().unwrap(), method);
+        }
+        assert_eq!("post".parse::<Method>().unwrap(), Method::Post);
+        assert!("BREW".parse::<Method>().is_err());
+    }
+
+    #[test]
+    fn head_has_headers_body_length_and_cookies() {
+        let request = Request::post("http://example.com:8080/form?x=1".parse().unwrap())
+            .with_header("Content-Type", "text/plain")
+            .with_header("User-Agent", "curl/8")
+            .with_header("Cookie", "mine=1")
+            .with_body("hello");
+        assert_eq!(
+            request.wire_head(Some("jar=2")).unwrap(),
+            concat!(
+                "POST /form?x=1 HTTP/1.1\r\n",
+                "Host: example.com:8080\r\n",
+                "Connection: close\r\n",
+                "Content-Length: 5\r\n",
+                "Content-Type: text/plain\r\n",
+                "User-Agent: curl/8\r\n",
+                "Cookie: mine=1; jar=2\r\n",
+                "\r\n"
+            )
+        );
+
+        let request = Request::get("http://example.com/".parse().unwrap());
+        assert_eq!(
+            request.wire_head(None).unwrap(),
+            concat!(
+                "GET / HTTP/1.1\r\n",
+                "Host: example.com\r\n",
+                "Connection: close\r\n",
+                "User-Agent: browser_rust\r\n",
+                "\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn refuses_headers_that_would_break_the_head() {
+        let url: URL = "http://example.com/".parse().unwrap();
+        let head = |name: &str, value: &str| {
+            Request::get(url.clone())
+                .with_header(name, value)
+                .wire_head(None)
+        };
+        assert!(head("X-Tab", "a\tb").is_ok());
+        assert!(matches!(
+            head("X-Evil", "1\r\nHost: other.example"),
+            Err(ParseError::InvalidHeader(name)) if name == "X-Evil"
+        ));
+        assert!(head("X-Evil", "1\n\r\nGET /smuggled HTTP/1.1").is_err());
+        assert!(head("Bad Name", "1").is_err());
+        assert!(head("Bad:Name", "1").is_err());
+        assert!(head("", "1").is_err());
+        assert!(
+            Request::get(url.clone())
+                .wire_head(Some("a=1\r\nX-Injected: 1"))
+                .is_err()
+        );
+
+        let url: URL = "http://example.com/a b HTTP/1.1\r\nX: y".parse().unwrap();
+        assert!(matches!(
+            Request::get(url).wire_head(None),
+            Err(ParseError::InvalidTarget)
+        ));
+    }
+}
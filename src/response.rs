@@ -1,21 +1,246 @@
-use std::fmt::Display;
-
-pub enum Response {
-    Http(String),
-    File(String),
-    Data(String),
-    ViewSource(Box<Response>),
-    None,
+use std::{borrow::Cow, fmt::Display, time::Duration};
+
+use crate::{
+    parser::{HttpResponseParseError, HttpResponseParser},
+    url::URL,
+};
+
+/// Where a response came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Http,
+    File,
+    Data,
+    /// The source of another response, to be shown as text rather than rendered
+    ViewSource,
+}
+
+/// One hop in a redirect chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// The url that was requested
+    pub url: URL,
+    pub status: u32,
+    /// Where it redirected to, resolved against `url`
+    pub location: URL,
+}
+
+/// How long each phase of the final request took. Phases that didnt happen, like tls for plain
+/// http or everything for a cached response, are zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timings {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Duration,
+    /// From sending the request to the first byte of the response
+    pub ttfb: Duration,
+    /// From the first byte of the response to the last
+    pub download: Duration,
+    /// The whole fetch, including any redirects
+    pub total: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    url: URL,
+    source: Source,
+    from_cache: bool,
+    http_version: String,
+    status: u32,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    redirects: Vec<Redirect>,
+    timings: Timings,
+}
+
+impl Response {
+    /// A `200 OK` response, which is what file and data urls produce
+    pub fn new(url: URL, source: Source, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            url,
+            source,
+            from_cache: false,
+            http_version: "HTTP/1.1".to_string(),
+            status: 200,
+            reason: "OK".to_string(),
+            headers: Vec::new(),
+            body: body.into(),
+            redirects: Vec::new(),
+            timings: Timings::default(),
+        }
+    }
+
+    /// Parses a whole http message, as read off the wire once any chunked encoding is undone
+    pub fn from_http(url: URL, message: &[u8]) -> Result<Self, HttpResponseParseError> {
+        let head_end = message
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or(HttpResponseParseError::MalformedHeader)?;
+        let head = String::from_utf8_lossy(&message[..head_end + 4]);
+        let parsed = HttpResponseParser::parse(&head)?;
+
+        let mut response = Self::new(url, Source::Http, &message[head_end + 4..]);
+        response.http_version = parsed.http_version().to_string();
+        response.status = parsed.status();
+        response.reason = parsed.status_message().to_string();
+        response.headers = parsed.header_list().to_vec();
+        Ok(response)
+    }
+
+    /// The response as an http message, how it is kept in the cache
+    pub fn to_http(&self) -> Vec<u8> {
+        let mut message = format!("{} {} {}\r\n", self.http_version, self.status, self.reason);
+        for (name, value) in &self.headers {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        message.push_str("\r\n");
+        let mut message = message.into_bytes();
+        message.extend_from_slice(&self.body);
+        message
+    }
+
+    /// The final url, after any redirects
+    pub fn url(&self) -> &URL {
+        &self.url
+    }
+
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    pub fn from_cache(&self) -> bool {
+        self.from_cache
+    }
+
+    pub fn http_version(&self) -> &str {
+        &self.http_version
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Every header in the order received, including repeats
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// First value of a header, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of a repeated header, ignoring case
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// The body as text, with invalid utf-8 replaced
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// Every redirect followed to get here, in order
+    pub fn redirects(&self) -> &[Redirect] {
+        &self.redirects
+    }
+
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    pub(crate) fn take_body(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+
+    pub(crate) fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    pub(crate) fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.into()));
+    }
+
+    pub(crate) fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub(crate) fn set_url(&mut self, url: URL) {
+        self.url = url;
+    }
+
+    pub(crate) fn set_source(&mut self, source: Source) {
+        self.source = source;
+    }
+
+    pub(crate) fn set_from_cache(&mut self, from_cache: bool) {
+        self.from_cache = from_cache;
+    }
+
+    pub(crate) fn set_redirects(&mut self, redirects: Vec<Redirect>) {
+        self.redirects = redirects;
+    }
+
+    pub(crate) fn timings_mut(&mut self) -> &mut Timings {
+        &mut self.timings
+    }
 }
 
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Response::Http(res) => write!(f, "{}", res),
-            Response::File(res) => write!(f, "{}", res),
-            Response::Data(res) => write!(f, "{}", res),
-            Response::ViewSource(res) => write!(f, "{}", res),
-            Response::None => write!(f, "No response"),
-        }
+        write!(f, "{}", self.text())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn http_round_trip() {
+        let url: URL = "http://example.com/".parse().unwrap();
+        let message =
+            b"HTTP/1.1 404 Not Found\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\n\r\n\xffbody";
+        let response = Response::from_http(url.clone(), message).unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.reason(), "Not Found");
+        assert!(!response.is_success());
+        assert_eq!(
+            response.header_values("Set-Cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert_eq!(response.body(), b"\xffbody");
+        assert_eq!(response.text(), "\u{fffd}body");
+        assert_eq!(response.to_http(), message);
+
+        let response = Response::from_http(url, b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        assert_eq!(response.status(), 204);
+        assert!(response.headers().is_empty() && response.body().is_empty());
     }
 }
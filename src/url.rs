@@ -2,7 +2,7 @@ use std::{fmt::Display, str::FromStr};
 
 use thiserror::Error;
 
-const ALLOWED_SCHEMES: [&'static str; 5] = ["http", "https", "file", "data", "view-source"];
+const ALLOWED_SCHEMES: [&str; 5] = ["http", "https", "file", "data", "view-source"];
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum Scheme {
@@ -26,15 +26,24 @@ impl Display for Scheme {
         write!(f, "{}", scheme)
     }
 }
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct URL {
     serialization: String,
     scheme_end: usize, // does not include ://, and is exclusive ie one more than the actually
     // scheme
+    // where the userinfo's @ is, when there is one
+    userinfo_end: Option<usize>,
     host_end: Option<usize>,
     path_end: Option<usize>,
     data_end: Option<usize>,
 }
 
+impl Display for URL {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.serialization)
+    }
+}
+
 #[allow(unused)]
 impl URL {
     pub fn scheme(&self) -> Scheme {
@@ -50,22 +59,56 @@ impl URL {
 
     pub fn host(&self) -> Option<&str> {
         if let Some(host_end) = self.host_end {
-            //skip ://
-            Some(&self.serialization[self.scheme_end + 3..host_end])
+            //skip :// or the userinfo
+            let start = self.userinfo_end.map_or(self.scheme_end + 3, |end| end + 1);
+            Some(&self.serialization[start..host_end])
         } else {
             None
         }
     }
-    pub fn port(&self) -> Option<u32> {
-        let Some(host) = self.host() else {
-            return None;
+
+    /// The user from `user:password@host`, still percent-encoded
+    pub fn username(&self) -> Option<&str> {
+        let userinfo = &self.serialization[self.scheme_end + 3..self.userinfo_end?];
+        Some(userinfo.split_once(':').map_or(userinfo, |(user, _)| user))
+    }
+
+    /// The password from `user:password@host`, still percent-encoded
+    pub fn password(&self) -> Option<&str> {
+        let userinfo = &self.serialization[self.scheme_end + 3..self.userinfo_end?];
+        userinfo.split_once(':').map(|(_, password)| password)
+    }
+
+    /// The same url without `user:password@`, for anywhere it could be seen or stored
+    pub fn without_userinfo(&self) -> URL {
+        let Some(end) = self.userinfo_end else {
+            return self.clone();
         };
-        match host.split_once(":") {
-            Some(port) => port.1.parse::<u32>().ok(),
-            None => None,
+        let start = self.scheme_end + 3;
+        let removed = end + 1 - start;
+        let shift = |index: Option<usize>| index.map(|index| index - removed);
+        URL {
+            serialization: format!(
+                "{}{}",
+                &self.serialization[..start],
+                &self.serialization[end + 1..]
+            ),
+            scheme_end: self.scheme_end,
+            userinfo_end: None,
+            host_end: shift(self.host_end),
+            path_end: shift(self.path_end),
+            data_end: shift(self.data_end),
         }
     }
 
+    /// The host without its port
+    pub fn hostname(&self) -> Option<&str> {
+        Some(split_port(self.host()?).0)
+    }
+    pub fn port(&self) -> Option<u32> {
+        split_port(self.host()?).1?.parse::<u32>().ok()
+    }
+
     pub fn path(&self) -> Option<&str> {
         let path_end = self.path_end?;
 
@@ -77,6 +120,55 @@ impl URL {
         }
     }
 
+    /// Resolves a possibly relative reference, like a link or a Location header, against this url
+    pub fn join(&self, reference: &str) -> Result<URL, ParseError> {
+        let reference = reference.trim();
+        let has_scheme = reference
+            .find(':')
+            .is_some_and(|colon| !reference[..colon].contains(['/', '?', '#']));
+        if has_scheme {
+            return reference.parse();
+        }
+        let scheme = &self.serialization[..self.scheme_end];
+        if let Some(rest) = reference.strip_prefix("//") {
+            return format!("{}://{}", scheme, rest).parse();
+        }
+
+        let origin = match self.host() {
+            Some(host) => format!("{}://{}", scheme, host),
+            None => format!("{}:", scheme),
+        };
+        let path = self.path().ok_or(ParseError::HostMissing)?;
+        let (path, query) = match path
+            .split_once('#')
+            .map_or(path, |(path, _)| path)
+            .split_once('?')
+        {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+
+        let joined = if reference.is_empty() {
+            match query {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            }
+        } else if reference.starts_with('#') {
+            match query {
+                Some(query) => format!("{}?{}{}", path, query, reference),
+                None => format!("{}{}", path, reference),
+            }
+        } else if reference.starts_with('?') {
+            format!("{}{}", path, reference)
+        } else if reference.starts_with('/') {
+            remove_dot_segments(reference)
+        } else {
+            let directory = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+            remove_dot_segments(&format!("{}{}", directory, reference))
+        };
+        format!("{}{}", origin, joined).parse()
+    }
+
     pub fn data(&self) -> Option<&str> {
         if let Some(data_end) = self.data_end {
             Some(&self.serialization[self.scheme_end + 1..data_end])
@@ -86,6 +178,67 @@ impl URL {
     }
 }
 
+/// Splits `host:port`, taking the brackets off an ipv6 address like `[::1]:8080`
+pub(crate) fn split_port(host: &str) -> (&str, Option<&str>) {
+    if let Some(bracketed) = host.strip_prefix('[')
+        && let Some((address, rest)) = bracketed.split_once(']')
+    {
+        return (address, rest.strip_prefix(':'));
+    }
+    match host.split_once(':') {
+        Some((name, port)) => (name, Some(port)),
+        None => (host, None),
+    }
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are and replacing invalid utf-8
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Resolves `.` and `..` in a path, leaving any query or fragment alone
+fn remove_dot_segments(path: &str) -> String {
+    let split = path.find(['?', '#']).unwrap_or(path.len());
+    let (path, rest) = path.split_at(split);
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    segments.pop();
+                }
+                // a trailing dot segment still means a directory
+                if last {
+                    segments.push("");
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}{}", segments.join("/"), rest)
+}
+
 #[derive(Debug, Error)]
 pub enum ParseError {
     //pub for some reason
@@ -97,8 +250,20 @@ pub enum ParseError {
     #[error("missing host")]
     HostMissing,
 
+    #[error("missing path")]
+    PathMissing,
+
     #[error("unknown scheme")]
     UnknownScheme,
+
+    #[error("invalid host")]
+    InvalidHost,
+
+    #[error("request target has spaces or control characters")]
+    InvalidTarget,
+
+    #[error("invalid header {0:?}")]
+    InvalidHeader(String),
 }
 
 impl FromStr for URL {
@@ -118,7 +283,8 @@ impl FromStr for URL {
             return Err(ParseError::UnknownScheme);
         }
 
-        let mut host_end = None; // start assuming there is no host
+        let mut userinfo_end = None; // start assuming there is no userinfo
+        let mut host_end = None; // or host
         let mut path_end = None; // and that there is no path
         let mut data_end = None; // and there is no data
 
@@ -139,6 +305,9 @@ impl FromStr for URL {
                         Some(s.len() - 1)
                     } //end of host is end of string
                 };
+                userinfo_end = host_end
+                    .and_then(|end| s[after_scheme..end].rfind('@'))
+                    .map(|at| after_scheme + at);
             }
             path_end = Some(s.len());
         } else {
@@ -149,6 +318,7 @@ impl FromStr for URL {
         Ok(Self {
             serialization: s,
             scheme_end,
+            userinfo_end,
             host_end,
             path_end,
             data_end,
@@ -160,6 +330,18 @@ impl FromStr for URL {
 mod test {
     use super::*;
 
+    #[test]
+    fn ipv6_hosts() {
+        let url = "http://[::1]:8080/".parse::<URL>().unwrap();
+        assert_eq!(url.host(), Some("[::1]:8080"));
+        assert_eq!(url.hostname(), Some("::1"));
+        assert_eq!(url.port(), Some(8080));
+
+        let url = "http://[::1]/".parse::<URL>().unwrap();
+        assert_eq!(url.hostname(), Some("::1"));
+        assert_eq!(url.port(), None);
+    }
+
     #[test]
     fn http_url() {
         let url = "http://www.google.com".parse::<URL>().unwrap();
@@ -221,6 +403,57 @@ mod test {
         assert_eq!(url.port(), Some(8080))
     }
 
+    #[test]
+    fn userinfo() {
+        let url: URL = "http://al%40ce:p:w@example.com:8080/a@b".parse().unwrap();
+        assert_eq!(url.username(), Some("al%40ce"));
+        assert_eq!(url.password(), Some("p:w"));
+        assert_eq!(url.host(), Some("example.com:8080"));
+        assert_eq!(url.hostname(), Some("example.com"));
+        assert_eq!(url.port(), Some(8080));
+        assert_eq!(url.path(), Some("/a@b"));
+        let stripped = url.without_userinfo();
+        assert_eq!(stripped.to_string(), "http://example.com:8080/a@b");
+        assert_eq!(stripped.username(), None);
+        assert_eq!(stripped.host(), Some("example.com:8080"));
+        assert_eq!(stripped.path(), Some("/a@b"));
+
+        let url: URL = "http://user@example.com".parse().unwrap();
+        assert_eq!(url.username(), Some("user"));
+        assert_eq!(url.password(), None);
+        let url: URL = "http://example.com/a@b".parse().unwrap();
+        assert_eq!(url.username(), None);
+        assert_eq!(url.host(), Some("example.com"));
+    }
+
+    #[test]
+    fn join() {
+        let base: URL = "http://example.com:8080/a/b/c?q=1#top".parse().unwrap();
+        let join = |reference: &str| base.join(reference).unwrap().to_string();
+        assert_eq!(join("https://other.org/x"), "https://other.org/x");
+        assert_eq!(
+            join("//cdn.example.com/lib.js"),
+            "http://cdn.example.com/lib.js"
+        );
+        assert_eq!(join("/root"), "http://example.com:8080/root");
+        assert_eq!(join("d"), "http://example.com:8080/a/b/d");
+        assert_eq!(join("./d/"), "http://example.com:8080/a/b/d/");
+        assert_eq!(join("../d?x=y"), "http://example.com:8080/a/d?x=y");
+        assert_eq!(join("../../../../d"), "http://example.com:8080/d");
+        assert_eq!(join(".."), "http://example.com:8080/a/");
+        assert_eq!(join("?z=2"), "http://example.com:8080/a/b/c?z=2");
+        assert_eq!(join("#end"), "http://example.com:8080/a/b/c?q=1#end");
+        assert_eq!(join(""), "http://example.com:8080/a/b/c?q=1");
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%E2%82%AC"), "€");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
     #[test]
     fn view_source_http() {
         let url: URL = "view-source:http://browser.engineering/examples/example1-simple.html"